@@ -1,44 +1,180 @@
+use crate::fees::FeeSchedule;
+use crate::ledger::{ClientJournal, DoubleEntryBook, Entry, LedgerAccount, Posting};
+use crate::metrics;
 use crate::output::AccountOutput;
-use crate::transaction::Tx;
+use crate::retention::{EvictedIds, Horizon};
+use crate::statement::{Statement, StatementEntry};
+use crate::transaction::{ClientId, Transaction, Tx, TxId};
 use rust_decimal::Decimal;
 use rust_decimal::prelude::*;
-use std::collections::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
 
+#[derive(Serialize, Deserialize)]
 pub struct Account {
     locked: bool,
-    client: u16,
+    client: ClientId,
     book: DoubleEntryBook,
-    disputed_txs: HashSet<u32>,
-    deposits: HashMap<u32, Decimal>,
-    withdraws: HashMap<u32, Decimal>,
+    disputed_txs: HashSet<TxId>,
+    deposits: HashMap<TxId, Decimal>,
+    withdraws: HashMap<TxId, Decimal>,
+    flows: Flows,
+    credit_limit: Decimal,
+    fees: FeeSchedule,
+    // note: fees of deposits that are refunded when the deposit is charged back
+    deposit_fees: HashMap<TxId, Decimal>,
+    horizon: Option<Horizon>,
+    // note: logical clock, bumped by every transaction the account sees
+    seen: u64,
+    // note: deposits and withdrawals by the time they were applied, oldest first
+    retained: VecDeque<(u64, TxId)>,
+    // note: ids dropped past the horizon, still caught as duplicates
+    evicted: EvictedIds,
+    // note: only kept for clients a statement was asked for
+    history: Option<Vec<StatementEntry>>,
+    // note: the run that last configured the account, zero until the first one
+    attached: u64,
 }
 
-pub struct DoubleEntryBook {
-    pub available_funds: Decimal,
-    pub held_funds: Decimal,
-    pub total_funds: Decimal,
+/// why an operation left the account untouched
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rejection {
+    Locked,
+    DuplicateTx,
+    NonPositiveAmount,
+    InsufficientFunds,
+    UnknownClient,
+    UnknownTx,
+    AlreadyDisputed,
+    NotDisputed,
+    NegativeLimit,
+    BeyondDisputeHorizon,
 }
 
-impl DoubleEntryBook {
-    fn new() -> Self {
-        DoubleEntryBook {
-            available_funds: Decimal::ZERO,
-            held_funds: Decimal::ZERO,
-            total_funds: Decimal::ZERO,
+impl Rejection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::Locked => "locked",
+            Rejection::DuplicateTx => "duplicate_tx",
+            Rejection::NonPositiveAmount => "non_positive_amount",
+            Rejection::InsufficientFunds => "insufficient_funds",
+            Rejection::UnknownClient => "unknown_client",
+            Rejection::UnknownTx => "unknown_tx",
+            Rejection::AlreadyDisputed => "already_disputed",
+            Rejection::NotDisputed => "not_disputed",
+            Rejection::NegativeLimit => "negative_limit",
+            Rejection::BeyondDisputeHorizon => "beyond_dispute_horizon",
         }
     }
 }
 
+/// money that moved in or out of the account over its lifetime
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Flows {
+    pub deposited: Decimal,
+    pub withdrawn: Decimal,
+    pub charged_back: Decimal,
+    /// fees charged less fees refunded
+    pub fees: Decimal,
+}
+
 impl Account {
-    pub fn new(client: u16) -> Self {
+    pub fn new(client: ClientId) -> Self {
         Account {
             client,
             book: DoubleEntryBook::new(),
             disputed_txs: HashSet::new(),
             deposits: HashMap::new(),
             withdraws: HashMap::new(),
+            flows: Flows::default(),
+            credit_limit: Decimal::ZERO,
+            fees: FeeSchedule::default(),
+            deposit_fees: HashMap::new(),
+            horizon: None,
+            seen: 0,
+            retained: VecDeque::new(),
+            evicted: EvictedIds::default(),
             locked: false,
+            history: None,
+            attached: 0,
+        }
+    }
+
+    /// the run that last configured the account, zero for a fresh account
+    pub fn attached(&self) -> u64 {
+        self.attached
+    }
+
+    pub fn attach(&mut self, run: u64) {
+        self.attached = run;
+    }
+
+    /// records every transaction applied through `apply` from now on
+    pub fn keep_history(&mut self) {
+        self.history.get_or_insert_with(Vec::new);
+    }
+
+    /// keeps every journal entry the book posts from now on
+    pub fn keep_journal(&mut self) {
+        self.book.keep_entries();
+    }
+
+    pub fn take_journal(&mut self) -> Option<ClientJournal> {
+        self.book.take_entries().map(|entries| ClientJournal {
+            client: self.client,
+            entries,
+        })
+    }
+
+    fn post(
+        &mut self,
+        tx: TxId,
+        kind: Posting,
+        debit: LedgerAccount,
+        credit: LedgerAccount,
+        amount: Decimal,
+    ) {
+        self.book.post(Entry {
+            tx,
+            kind,
+            debit,
+            credit,
+            amount,
+        });
+    }
+
+    /// hands out the recorded history, `None` when history is not kept
+    pub fn take_statement(&mut self) -> Option<Statement> {
+        self.history.take().map(|entries| Statement {
+            client: self.client,
+            entries,
+        })
+    }
+
+    pub fn apply(&mut self, transaction: Transaction) -> Result<(), Rejection> {
+        self.seen += 1;
+        self.evict();
+        let fees = self.flows.fees;
+        let outcome = match transaction {
+            Transaction::Deposit(tx) => self.deposit(tx),
+            Transaction::PendingWithdrawal(tx) => self.withdraw(tx),
+            Transaction::Dispute(tx) => self.dispute(tx),
+            Transaction::Resolve(tx) => self.resolve(tx),
+            Transaction::Chargeback(tx) => self.chargeback(tx),
+            Transaction::CreditLimit(tx) => self.set_credit_limit(tx.amount),
+        };
+        if let Some(history) = self.history.as_mut() {
+            history.push(StatementEntry {
+                transaction,
+                rejection: outcome.err(),
+                fee: self.flows.fees - fees,
+                available: self.book.available_funds(),
+                held: self.book.held_funds(),
+                total: self.book.total_funds(),
+                locked: self.locked,
+            });
         }
+        outcome
     }
 
     pub fn locked(&self) -> bool {
@@ -49,106 +185,311 @@ impl Account {
         &self.book
     }
 
-    pub fn client(&self) -> u16 {
+    #[cfg(test)]
+    pub(crate) fn book_mut(&mut self) -> &mut DoubleEntryBook {
+        &mut self.book
+    }
+
+    pub fn client(&self) -> ClientId {
         self.client
     }
 
-    pub fn deposit(&mut self, tx: Tx) {
-        if self.locked {
-            return;
+    pub fn flows(&self) -> &Flows {
+        &self.flows
+    }
+
+    pub fn credit_limit(&self) -> Decimal {
+        self.credit_limit
+    }
+
+    /// the deposits under dispute, what `held` should add up to
+    pub fn disputed_amount(&self) -> Decimal {
+        self.disputed_txs
+            .iter()
+            .filter_map(|tx| self.deposits.get(tx))
+            .sum()
+    }
+
+    /// how much of the credit line the account is drawing on
+    pub fn credit_used(&self) -> Decimal {
+        (-self.book.available_funds()).max(Decimal::ZERO)
+    }
+
+    /// deposits and withdrawals older than `horizon` are dropped and can no
+    /// longer be disputed, `None` keeps them forever
+    pub fn set_horizon(&mut self, horizon: Option<Horizon>) {
+        if horizon.is_none() {
+            self.retained.clear();
         }
-        if self.deposits.contains_key(&tx.id) {
+        self.horizon = horizon;
+    }
+
+    fn retain(&mut self, tx: TxId) {
+        if self.horizon.is_some() {
+            self.retained.push_back((self.seen, tx));
+        }
+    }
+
+    fn evict(&mut self) {
+        let horizon = match self.horizon {
+            Some(Horizon::Transactions(n)) => n,
+            None => return,
+        };
+        let now = self.seen;
+        while let Some(&(at, tx)) = self.retained.front() {
+            if now.saturating_sub(at) < horizon {
+                break;
+            }
+            self.retained.pop_front();
+            // note: a deposit under dispute stays until it is resolved or charged back
+            if self.disputed_txs.contains(&tx) {
+                self.retained.push_back((now, tx));
+                continue;
+            }
+            self.deposits.remove(&tx);
+            self.withdraws.remove(&tx);
+            self.deposit_fees.remove(&tx);
+            self.evicted.insert(tx);
+            metrics::TRANSACTIONS_EVICTED.inc();
+        }
+    }
+
+    /// the disputable amount of a deposit
+    fn deposited(&self, tx: TxId) -> Result<Decimal, Rejection> {
+        match self.deposits.get(&tx) {
+            Some(&amount) => Ok(amount),
+            None if self.evicted.contains(tx) => Err(Rejection::BeyondDisputeHorizon),
+            None => Err(Rejection::UnknownTx),
+        }
+    }
+
+    pub fn set_fees(&mut self, fees: FeeSchedule) {
+        self.fees = fees;
+    }
+
+    fn charge_fee(&mut self, tx: TxId, kind: Posting, fee: Decimal) {
+        if fee.is_zero() {
             return;
         }
+        self.post(
+            tx,
+            kind,
+            LedgerAccount::Available,
+            LedgerAccount::FeeRevenue,
+            fee,
+        );
+        self.flows.fees += fee;
+    }
+
+    /// a lower limit does not touch an existing overdraft, it only stops
+    /// further withdrawals until the account is back above `-limit`
+    pub fn set_credit_limit(&mut self, limit: Decimal) -> Result<(), Rejection> {
+        if limit.is_sign_negative() {
+            return Err(Rejection::NegativeLimit);
+        }
+        self.credit_limit = limit.round_dp(4);
+        Ok(())
+    }
+
+    pub fn deposit(&mut self, tx: Tx) -> Result<(), Rejection> {
+        if self.locked {
+            return Err(Rejection::Locked);
+        }
+        if self.deposits.contains_key(&tx.id) || self.evicted.contains(tx.id) {
+            return Err(Rejection::DuplicateTx);
+        }
 
         let amount = tx
             .amount
             .round_dp_with_strategy(4, RoundingStrategy::ToZero);
 
-        if amount.is_zero() {
-            return;
-        }
-        if amount.is_negative() {
-            return;
+        if amount.is_zero() || amount.is_sign_negative() {
+            return Err(Rejection::NonPositiveAmount);
         }
 
-        self.book.available_funds += amount;
-        self.book.total_funds += amount;
+        self.post(
+            tx.id,
+            Posting::Deposit,
+            LedgerAccount::PartnerSettlement,
+            LedgerAccount::Available,
+            amount,
+        );
         self.deposits.insert(tx.id, amount);
+        self.retain(tx.id);
+        self.flows.deposited += amount;
+
+        // note: the fee never takes more than the deposit brought in
+        let fee = self.fees.deposit.on(amount).min(amount);
+        if !fee.is_zero() {
+            self.charge_fee(tx.id, Posting::DepositFee, fee);
+            self.deposit_fees.insert(tx.id, fee);
+        }
+        Ok(())
     }
 
-    pub fn withdraw(&mut self, tx: Tx) {
+    pub fn withdraw(&mut self, tx: Tx) -> Result<(), Rejection> {
         if self.locked {
-            return;
+            return Err(Rejection::Locked);
         }
-        if self.withdraws.contains_key(&tx.id) {
-            return;
+        if self.withdraws.contains_key(&tx.id) || self.evicted.contains(tx.id) {
+            return Err(Rejection::DuplicateTx);
         }
 
         let amount = tx.amount.round_dp(4);
 
-        if amount.is_zero() {
-            return;
+        if amount.is_zero() || amount.is_sign_negative() {
+            return Err(Rejection::NonPositiveAmount);
         }
-        if amount.is_negative() {
-            return;
-        }
-        if (self.book.available_funds - amount).is_negative() {
-            return;
+        let fee = self.fees.withdraw.on(amount);
+        if self.book.available_funds() - amount - fee < -self.credit_limit {
+            return Err(Rejection::InsufficientFunds);
         }
 
-        self.book.available_funds -= amount;
-        self.book.total_funds -= amount;
+        self.post(
+            tx.id,
+            Posting::Withdraw,
+            LedgerAccount::Available,
+            LedgerAccount::PartnerSettlement,
+            amount,
+        );
         self.withdraws.insert(tx.id, amount);
+        self.retain(tx.id);
+        self.flows.withdrawn += amount;
+        self.charge_fee(tx.id, Posting::WithdrawFee, fee);
+        Ok(())
     }
 
-    pub fn dispute(&mut self, tx: Tx) {
+    pub fn dispute(&mut self, tx: Tx) -> Result<(), Rejection> {
         if self.disputed_txs.contains(&tx.id) {
-            return;
+            return Err(Rejection::AlreadyDisputed);
         }
 
-        if let Some(&amount) = self.deposits.get(&tx.id) {
-            self.book.available_funds -= amount;
-            self.book.held_funds += amount;
-            self.disputed_txs.insert(tx.id);
-        }
+        let amount = self.deposited(tx.id)?;
+        self.post(
+            tx.id,
+            Posting::Dispute,
+            LedgerAccount::Available,
+            LedgerAccount::Held,
+            amount,
+        );
+        self.disputed_txs.insert(tx.id);
+        Ok(())
     }
 
-    pub fn resolve(&mut self, tx: Tx) {
+    pub fn resolve(&mut self, tx: Tx) -> Result<(), Rejection> {
         if !self.disputed_txs.contains(&tx.id) {
-            return;
+            return Err(Rejection::NotDisputed);
         }
 
-        if let Some(&amount) = self.deposits.get(&tx.id) {
-            self.disputed_txs.remove(&tx.id);
-            self.book.held_funds -= amount;
-            self.book.available_funds += amount;
-        }
+        let amount = self.deposited(tx.id)?;
+        self.disputed_txs.remove(&tx.id);
+        self.post(
+            tx.id,
+            Posting::Resolve,
+            LedgerAccount::Held,
+            LedgerAccount::Available,
+            amount,
+        );
+        Ok(())
     }
 
-    pub fn chargeback(&mut self, tx: Tx) {
+    pub fn chargeback(&mut self, tx: Tx) -> Result<(), Rejection> {
         if !self.disputed_txs.contains(&tx.id) {
-            return;
+            return Err(Rejection::NotDisputed);
         }
 
-        if let Some(&amount) = self.deposits.get(&tx.id) {
-            self.disputed_txs.remove(&tx.id);
-            self.book.held_funds -= amount;
-            self.book.total_funds -= amount;
+        let amount = self.deposited(tx.id)?;
+        self.disputed_txs.remove(&tx.id);
+        self.post(
+            tx.id,
+            Posting::Chargeback,
+            LedgerAccount::Held,
+            LedgerAccount::ChargebackLosses,
+            amount,
+        );
+        self.flows.charged_back += amount;
+
+        // note: the charged back deposit never happened, so neither did its fee
+        if let Some(fee) = self.deposit_fees.remove(&tx.id) {
+            self.post(
+                tx.id,
+                Posting::DepositFeeRefund,
+                LedgerAccount::FeeRevenue,
+                LedgerAccount::Available,
+                fee,
+            );
+            self.flows.fees -= fee;
         }
+        let fee = self.fees.chargeback.on(amount);
+        self.charge_fee(tx.id, Posting::ChargebackFee, fee);
         self.locked = true;
+        Ok(())
     }
 }
 
-impl Into<AccountOutput> for Account {
-    fn into(self) -> AccountOutput {
-        let book = self.book();
+impl From<&Account> for AccountOutput {
+    fn from(account: &Account) -> Self {
+        let book = account.book();
         AccountOutput {
-            client: self.client,
-            total: book.total_funds,
-            available: book.available_funds,
-            held: book.held_funds,
-            locked: self.locked,
+            client: account.client,
+            total: book.total_funds(),
+            available: book.available_funds(),
+            held: book.held_funds(),
+            locked: account.locked,
+            credit_limit: account.credit_limit,
+            credit_used: account.credit_used(),
+        }
+    }
+}
+
+impl From<Account> for AccountOutput {
+    fn from(account: Account) -> Self {
+        AccountOutput::from(&account)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx(id: TxId, amount: i64) -> Tx {
+        Tx {
+            client: 1,
+            id,
+            amount: Decimal::from(amount),
+        }
+    }
+
+    #[test]
+    fn evicted_deposit_is_still_a_duplicate() {
+        let mut account = Account::new(1);
+        account.set_horizon(Some(Horizon::Transactions(2)));
+        for (id, amount) in [(1, 10), (2, 5), (3, 5)] {
+            account.apply(Transaction::Deposit(tx(id, amount))).unwrap();
+        }
+        assert!(!account.deposits.contains_key(&1));
+        assert_eq!(
+            account.apply(Transaction::Deposit(tx(1, 10))),
+            Err(Rejection::DuplicateTx)
+        );
+        assert_eq!(account.book().total_funds(), Decimal::from(20));
+    }
+
+    #[test]
+    fn only_evicted_ids_are_beyond_the_horizon() {
+        let mut account = Account::new(1);
+        account.set_horizon(Some(Horizon::Transactions(2)));
+        for (id, amount) in [(10, 10), (20, 5), (30, 5)] {
+            account.apply(Transaction::Deposit(tx(id, amount))).unwrap();
         }
+        assert_eq!(
+            account.apply(Transaction::Dispute(tx(10, 0))),
+            Err(Rejection::BeyondDisputeHorizon)
+        );
+        // note: below the newest evicted id but never seen by this client
+        assert_eq!(
+            account.apply(Transaction::Dispute(tx(5, 0))),
+            Err(Rejection::UnknownTx)
+        );
     }
 }
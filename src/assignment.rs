@@ -0,0 +1,229 @@
+use crate::io::count_client_transactions;
+use crate::transaction::ClientId;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::info;
+
+// points each shard places on the consistent hash ring
+const VIRTUAL_NODES: u64 = 128;
+
+/// decides which shard owns a client
+///
+/// a client must map to the same shard for the whole run, accounts never move
+/// between workers
+pub trait ShardAssignment: Send + Sync {
+    fn shard(&self, client: ClientId) -> usize;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    Modulo,
+    ConsistentHash,
+    Static,
+    PreScan,
+}
+
+pub struct Modulo {
+    shards: usize,
+}
+
+impl Modulo {
+    pub fn new(shards: usize) -> Self {
+        Modulo { shards }
+    }
+}
+
+impl ShardAssignment for Modulo {
+    fn shard(&self, client: ClientId) -> usize {
+        (client % self.shards as u64) as usize
+    }
+}
+
+// note: splitmix64 finaliser, a fixed hash keeps the ring stable across runs
+pub(crate) fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// clients keep their shard when the worker count changes, except for the
+/// share the added or removed shards take over
+pub struct ConsistentHash {
+    ring: Vec<(u64, usize)>,
+}
+
+impl ConsistentHash {
+    pub fn new(shards: usize) -> Self {
+        let mut ring = vec![];
+        for shard in 0..shards {
+            for node in 0..VIRTUAL_NODES {
+                ring.push((mix(((shard as u64) << 32) | node), shard));
+            }
+        }
+        ring.sort_unstable();
+        ConsistentHash { ring }
+    }
+}
+
+impl ShardAssignment for ConsistentHash {
+    fn shard(&self, client: ClientId) -> usize {
+        let point = mix(client);
+        let idx = self.ring.partition_point(|&(p, _)| p < point);
+        self.ring[idx % self.ring.len()].1
+    }
+}
+
+/// a precomputed shard per client id, clients missing from the table fall
+/// back to modulo
+pub struct Table {
+    clients: HashMap<ClientId, usize>,
+    fallback: Modulo,
+}
+
+impl ShardAssignment for Table {
+    fn shard(&self, client: ClientId) -> usize {
+        match self.clients.get(&client) {
+            Some(&shard) => shard,
+            None => self.fallback.shard(client),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MapRow {
+    client: ClientId,
+    shard: usize,
+}
+
+impl Table {
+    fn modulo(shards: usize) -> Self {
+        Table {
+            clients: HashMap::new(),
+            fallback: Modulo::new(shards),
+        }
+    }
+
+    /// loads a `client,shard` csv, clients missing from the file fall back to modulo
+    pub fn load(path: &Path, shards: usize) -> anyhow::Result<Self> {
+        let mut table = Table::modulo(shards);
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)?;
+        for row in reader.deserialize() {
+            let row: MapRow = row?;
+            if row.shard >= shards {
+                return Err(anyhow::anyhow!(
+                    "client {} is mapped to shard {} but there are only {} shards",
+                    row.client,
+                    row.shard,
+                    shards
+                ));
+            }
+            table.clients.insert(row.client, row.shard);
+        }
+        Ok(table)
+    }
+
+    /// bin packs clients onto shards by transaction count, heaviest client first
+    /// onto the least loaded shard
+    pub fn pre_scan(counts: &HashMap<ClientId, u64>, shards: usize) -> Self {
+        let mut table = Table::modulo(shards);
+
+        let mut clients: Vec<(ClientId, u64)> = counts.iter().map(|(&c, &n)| (c, n)).collect();
+        clients.sort_unstable_by_key(|&(client, count)| (Reverse(count), client));
+
+        let mut loads: BinaryHeap<Reverse<(u64, usize)>> =
+            (0..shards).map(|shard| Reverse((0, shard))).collect();
+        for (client, count) in clients {
+            if let Some(Reverse((load, shard))) = loads.pop() {
+                table.clients.insert(client, shard);
+                loads.push(Reverse((load + count, shard)));
+            }
+        }
+
+        let mut loads = loads.into_vec();
+        loads.sort_unstable_by_key(|Reverse((_, shard))| *shard);
+        for Reverse((load, shard)) in loads {
+            info!(shard, transactions = load, "pre scan shard load");
+        }
+        table
+    }
+}
+
+pub fn build(
+    strategy: Strategy,
+    shards: usize,
+    map: Option<&Path>,
+    inputs: &[String],
+) -> anyhow::Result<Arc<dyn ShardAssignment>> {
+    let assignment: Arc<dyn ShardAssignment> = match strategy {
+        Strategy::Modulo => Arc::new(Modulo::new(shards)),
+        Strategy::ConsistentHash => Arc::new(ConsistentHash::new(shards)),
+        Strategy::Static => {
+            let map = map.ok_or_else(|| anyhow::anyhow!("static assignment needs a map file"))?;
+            Arc::new(Table::load(map, shards)?)
+        }
+        Strategy::PreScan => {
+            let counts = count_client_transactions(inputs)?;
+            Arc::new(Table::pre_scan(&counts, shards))
+        }
+    };
+    Ok(assignment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn modulo_spreads_clients_round_robin() {
+        let modulo = Modulo::new(3);
+        let shards: Vec<usize> = (0..6).map(|client| modulo.shard(client)).collect();
+        assert_eq!(shards, [0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn consistent_hash_moves_only_the_added_shards_share() {
+        let (three, four) = (ConsistentHash::new(3), ConsistentHash::new(4));
+        let moved: Vec<ClientId> = (0..10_000u64)
+            .filter(|&client| three.shard(client) != four.shard(client))
+            .collect();
+        // note: every client that moved went to the new shard
+        assert!(moved.iter().all(|&client| four.shard(client) == 3));
+        assert!(
+            moved.len() > 1_000 && moved.len() < 4_000,
+            "{} moved",
+            moved.len()
+        );
+    }
+
+    #[test]
+    fn static_map_falls_back_to_modulo_and_checks_shards() {
+        let mut map = tempfile::NamedTempFile::new().unwrap();
+        writeln!(map, "client,shard\n7, 1\n8,1").unwrap();
+        let table = Table::load(map.path(), 2).unwrap();
+        assert_eq!(table.shard(7), 1);
+        assert_eq!(table.shard(8), 1);
+        assert_eq!(table.shard(10), 0);
+
+        let error = Table::load(map.path(), 1).err().unwrap();
+        assert!(error.to_string().contains("only 1 shards"), "{error}");
+        assert!(build(Strategy::Static, 2, None, &[]).is_err());
+    }
+
+    #[test]
+    fn pre_scan_puts_the_heaviest_clients_apart() {
+        let counts = HashMap::from([(1, 100), (2, 90), (3, 10), (4, 5)]);
+        let table = Table::pre_scan(&counts, 2);
+        assert_ne!(table.shard(1), table.shard(2));
+        assert_eq!(table.shard(2), table.shard(3));
+        // note: both shards are at 100, the tie goes to the lower shard
+        assert_eq!(table.shard(4), 0);
+    }
+}
@@ -0,0 +1,321 @@
+use clap::{Parser, ValueEnum};
+use kraken::logging::{self, LogFormat};
+use kraken::output::write_output_accounts_to;
+use kraken::reference::ReferenceModel;
+use kraken::transaction::{ClientId, Transaction, Tx, TxId};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, Zipf};
+use rust_decimal::Decimal;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use tracing::info;
+
+// how many of a client's latest deposits and tx ids stay candidates for
+// disputes and duplicates, keeps memory flat for very large files
+const RECENT_TXS: usize = 64;
+// chance per row that one of the client's open disputes is settled
+const SETTLE_CHANCE: f64 = 0.5;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Skew {
+    Uniform,
+    Zipf,
+}
+
+#[derive(Parser)]
+#[command(
+    name = "kraken-gen",
+    about = "generates synthetic transaction csvs for kraken"
+)]
+struct Cli {
+    /// where the transactions csv is written
+    output: PathBuf,
+
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u32).range(1..))]
+    clients: u32,
+
+    /// id of the first client, ids past `u32::MAX` exercise wide client ids
+    #[arg(long, default_value_t = 1)]
+    first_client: ClientId,
+
+    /// how rows are spread over clients, zipf makes client 1 the hottest
+    #[arg(long, value_enum, default_value_t = Skew::Uniform)]
+    skew: Skew,
+
+    /// larger exponents concentrate rows on fewer clients
+    #[arg(long, default_value_t = 1.1)]
+    zipf_exponent: f64,
+
+    /// number of rows to write, ignored when --size is given
+    #[arg(long, default_value_t = 100_000)]
+    rows: u64,
+
+    /// stop once the file reaches this size i.e. 512M or 50G
+    #[arg(long, value_parser = parse_size)]
+    size: Option<u64>,
+
+    /// share of deposit and withdraw rows that are withdrawals
+    #[arg(long, default_value_t = 0.4, value_parser = parse_rate)]
+    withdraw_ratio: f64,
+
+    /// chance that a row disputes one of the client's recent deposits
+    #[arg(long, default_value_t = 0.02, value_parser = parse_rate)]
+    dispute_rate: f64,
+
+    /// share of settled disputes that end in a chargeback instead of a resolve
+    #[arg(long, default_value_t = 0.2, value_parser = parse_rate)]
+    chargeback_rate: f64,
+
+    /// chance that a deposit or withdrawal reuses one of the client's tx ids
+    #[arg(long, default_value_t = 0.0, value_parser = parse_rate)]
+    duplicate_rate: f64,
+
+    /// chance that a row has an unparseable amount or an unknown type, the
+    /// reader skips these rows without failing the file
+    #[arg(long, default_value_t = 0.0, value_parser = parse_rate)]
+    malformed_rate: f64,
+
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// also write the accounts the reference model ends with, this is what
+    /// `kraken --dispute-window-ms 0` must print
+    #[arg(long)]
+    expected: Option<PathBuf>,
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if (0.0..=1.0).contains(&rate) {
+        Ok(rate)
+    } else {
+        Err(format!("{} is not between 0 and 1", rate))
+    }
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((idx, _)) => s.split_at(idx),
+        None => (s, ""),
+    };
+    let n: u64 = digits.parse().map_err(|e| format!("{}", e))?;
+    let unit = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        other => return Err(format!("unknown size unit {}", other)),
+    };
+    n.checked_mul(unit)
+        .ok_or_else(|| "size overflows u64".to_string())
+}
+
+#[derive(Default)]
+struct ClientState {
+    // estimated available funds in ten thousandths, keeps most withdrawals valid
+    balance: i64,
+    recent_deposits: VecDeque<TxId>,
+    recent_txs: VecDeque<TxId>,
+    open_disputes: Vec<TxId>,
+}
+
+fn remember(recent: &mut VecDeque<TxId>, id: TxId) {
+    if recent.len() == RECENT_TXS {
+        recent.pop_front();
+    }
+    recent.push_back(id);
+}
+
+fn format_amount(ten_thousandths: i64) -> String {
+    format!(
+        "{}.{:04}",
+        ten_thousandths / 10_000,
+        ten_thousandths % 10_000
+    )
+}
+
+struct Generator {
+    cli: Cli,
+    rng: StdRng,
+    zipf: Option<Zipf<f64>>,
+    clients: Vec<ClientState>,
+    next_id: TxId,
+}
+
+impl Generator {
+    fn new(cli: Cli) -> anyhow::Result<Self> {
+        if cli
+            .first_client
+            .checked_add(cli.clients as ClientId)
+            .is_none()
+        {
+            return Err(anyhow::anyhow!(
+                "{} clients starting at {} overflow the client id",
+                cli.clients,
+                cli.first_client
+            ));
+        }
+        let zipf = match cli.skew {
+            Skew::Uniform => None,
+            Skew::Zipf => Some(Zipf::new(cli.clients as f64, cli.zipf_exponent)?),
+        };
+        let clients = (0..cli.clients).map(|_| ClientState::default()).collect();
+        Ok(Self {
+            rng: StdRng::seed_from_u64(cli.seed),
+            zipf,
+            clients,
+            next_id: 1,
+            cli,
+        })
+    }
+
+    /// index of the client in `clients`, its id is `first_client` plus the index
+    fn pick_client(&mut self) -> usize {
+        match &self.zipf {
+            Some(zipf) => zipf.sample(&mut self.rng) as usize - 1,
+            None => self.rng.random_range(0..self.cli.clients as usize),
+        }
+    }
+
+    fn fresh_id(&mut self) -> anyhow::Result<TxId> {
+        let id = self.next_id;
+        self.next_id = self
+            .next_id
+            .checked_add(1)
+            .ok_or_else(|| anyhow::anyhow!("tx id space exhausted after {} rows", id))?;
+        Ok(id)
+    }
+
+    /// writes one csv row into `row` and returns the transaction the reader
+    /// will decode from it, `None` for rows the reader skips
+    fn next_row(&mut self, row: &mut String) -> anyhow::Result<Option<Transaction>> {
+        let idx = self.pick_client();
+        let client = self.cli.first_client + idx as ClientId;
+        let cli = &self.cli;
+        let state = &mut self.clients[idx];
+
+        if self.rng.random_bool(cli.malformed_rate) {
+            let id = self.fresh_id()?;
+            if self.rng.random_bool(0.5) {
+                write!(row, "deposit,{},{},12.3.4", client, id)?;
+            } else {
+                write!(row, "transfer,{},{},1.0000", client, id)?;
+            }
+            return Ok(None);
+        }
+
+        let reference = |id: TxId| Tx {
+            client,
+            id,
+            amount: Decimal::ZERO,
+        };
+
+        if !state.open_disputes.is_empty() && self.rng.random_bool(SETTLE_CHANCE) {
+            let idx = self.rng.random_range(0..state.open_disputes.len());
+            let id = state.open_disputes.swap_remove(idx);
+            return if self.rng.random_bool(cli.chargeback_rate) {
+                write!(row, "chargeback,{},{},", client, id)?;
+                Ok(Some(Transaction::Chargeback(reference(id))))
+            } else {
+                write!(row, "resolve,{},{},", client, id)?;
+                Ok(Some(Transaction::Resolve(reference(id))))
+            };
+        }
+
+        if !state.recent_deposits.is_empty() && self.rng.random_bool(cli.dispute_rate) {
+            let idx = self.rng.random_range(0..state.recent_deposits.len());
+            let id = state.recent_deposits[idx];
+            state.open_disputes.push(id);
+            write!(row, "dispute,{},{},", client, id)?;
+            return Ok(Some(Transaction::Dispute(reference(id))));
+        }
+
+        let id = if !state.recent_txs.is_empty() && self.rng.random_bool(cli.duplicate_rate) {
+            state.recent_txs[self.rng.random_range(0..state.recent_txs.len())]
+        } else {
+            self.fresh_id()?
+        };
+        let state = &mut self.clients[idx];
+        remember(&mut state.recent_txs, id);
+
+        if state.balance > 0 && self.rng.random_bool(self.cli.withdraw_ratio) {
+            let amount = self.rng.random_range(1..=state.balance.max(2) / 2);
+            state.balance -= amount;
+            write!(row, "withdraw,{},{},{}", client, id, format_amount(amount))?;
+            let tx = Tx {
+                client,
+                id,
+                amount: Decimal::new(amount, 4),
+            };
+            return Ok(Some(Transaction::PendingWithdrawal(tx)));
+        }
+
+        let amount = self.rng.random_range(1..=100_000_000);
+        state.balance += amount;
+        remember(&mut state.recent_deposits, id);
+        write!(row, "deposit,{},{},{}", client, id, format_amount(amount))?;
+        let tx = Tx {
+            client,
+            id,
+            amount: Decimal::new(amount, 4),
+        };
+        Ok(Some(Transaction::Deposit(tx)))
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    logging::init("info", LogFormat::Text)?;
+
+    let output = cli.output.clone();
+    let expected = cli.expected.clone();
+    let (rows, size) = (cli.rows, cli.size);
+    let mut generator = Generator::new(cli)?;
+    let mut model = expected.as_ref().map(|_| ReferenceModel::new());
+
+    let mut out = BufWriter::new(File::create(&output)?);
+    let header = "type,client,tx,amount\n";
+    out.write_all(header.as_bytes())?;
+
+    let mut written_bytes = header.len() as u64;
+    let mut written_rows = 0u64;
+    let mut row = String::new();
+    loop {
+        let finished = match size {
+            Some(size) => written_bytes >= size,
+            None => written_rows >= rows,
+        };
+        if finished {
+            break;
+        }
+
+        row.clear();
+        let transaction = generator.next_row(&mut row)?;
+        row.push('\n');
+        out.write_all(row.as_bytes())?;
+        written_bytes += row.len() as u64;
+        written_rows += 1;
+
+        if let (Some(model), Some(transaction)) = (model.as_mut(), transaction) {
+            model.apply(transaction).ok();
+        }
+    }
+    out.flush()?;
+    info!(
+        file = %output.display(),
+        rows = written_rows,
+        bytes = written_bytes,
+        "wrote transactions"
+    );
+
+    if let (Some(path), Some(model)) = (expected, model) {
+        write_output_accounts_to(model.outputs(), BufWriter::new(File::create(&path)?))?;
+        info!(file = %path.display(), "wrote expected accounts");
+    }
+    Ok(())
+}
@@ -0,0 +1,235 @@
+use crate::assignment::Strategy;
+use crate::follow::FOLLOW_POLL_MILLISECONDS;
+use crate::io::{InputOrder, TX_CHUNK_SIZE};
+use crate::output::{OutputFormat, OutputOrder};
+use crate::retention::Horizon;
+use crate::shard::{DISPUTE_WINDOW_MILLISECONDS, IdleStrategy, PENDING_QUEUE_SIZE};
+use crate::store::STORE_CACHE_ACCOUNTS;
+use crate::topology::{self, CoreList};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// engine settings, read from a toml file with the same keys as the command
+/// line flags.  every key is optional and the command line wins over the file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// how rows of several input files are interleaved
+    pub input_order: InputOrder,
+    /// keep reading the one input as it grows until stopped
+    pub follow: bool,
+    /// how often a followed file is checked for new rows
+    pub follow_poll_ms: u64,
+    /// rows decoded and sent to the shards at a time
+    pub batch_size: usize,
+    /// one per worker core when not given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workers: Option<usize>,
+    #[serde(with = "text", skip_serializing_if = "Option::is_none")]
+    pub worker_cores: Option<CoreList>,
+    #[serde(with = "text", skip_serializing_if = "Option::is_none")]
+    pub reader_cores: Option<CoreList>,
+    pub pin: bool,
+    pub idle: IdleStrategy,
+    pub dispute_window_ms: u64,
+    #[serde(with = "text", skip_serializing_if = "Option::is_none")]
+    pub dispute_horizon: Option<Horizon>,
+    /// withdrawals each shard holds back at once
+    pub pending_queue: usize,
+    /// batches each shard channel holds, unbounded when not given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_capacity: Option<usize>,
+    pub assignment: Strategy,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub assignment_map: Option<PathBuf>,
+    pub format: OutputFormat,
+    pub order: OutputOrder,
+    pub credit_columns: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credit_limits: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fees: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_tiers: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub store: Option<PathBuf>,
+    pub store_cache: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            input_order: InputOrder::Sequential,
+            follow: false,
+            follow_poll_ms: FOLLOW_POLL_MILLISECONDS,
+            batch_size: TX_CHUNK_SIZE,
+            workers: None,
+            worker_cores: None,
+            reader_cores: None,
+            pin: true,
+            idle: IdleStrategy::Spin,
+            dispute_window_ms: DISPUTE_WINDOW_MILLISECONDS,
+            dispute_horizon: None,
+            pending_queue: PENDING_QUEUE_SIZE,
+            channel_capacity: None,
+            assignment: Strategy::Modulo,
+            assignment_map: None,
+            format: OutputFormat::Csv,
+            order: OutputOrder::Client,
+            credit_columns: false,
+            credit_limits: None,
+            fees: None,
+            fee_tiers: None,
+            store: None,
+            store_cache: STORE_CACHE_ACCOUNTS,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read config {}: {e}", path.display()))?;
+        toml::from_str(&text).map_err(|e| anyhow::anyhow!("bad config {}: {e}", path.display()))
+    }
+
+    /// fills in the worker cores and worker count left to the defaults
+    ///
+    /// workers get every core not given to the reader, one worker per core
+    pub fn resolve_layout(&mut self) -> anyhow::Result<()> {
+        let cores = match (&self.worker_cores, &self.reader_cores) {
+            (Some(CoreList(cores)), _) => cores.clone(),
+            (None, Some(CoreList(reader))) => topology::available_cores()
+                .into_iter()
+                .filter(|core| !reader.contains(core))
+                .collect(),
+            (None, None) => topology::available_cores(),
+        };
+        let workers = match (self.workers, cores.len()) {
+            (Some(workers), _) => workers,
+            (None, 0) => thread::available_parallelism()
+                .map(|n| n.get())
+                .map_err(|e| anyhow::anyhow!("failed to get available cores {:?}", e))?,
+            (None, n) => n,
+        };
+        self.pin = self.pin && !cores.is_empty();
+        self.worker_cores = (!cores.is_empty()).then_some(CoreList(cores));
+        self.workers = Some(workers);
+        Ok(())
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let positive = [
+            ("batch_size", Some(self.batch_size)),
+            ("follow_poll_ms", Some(self.follow_poll_ms as usize)),
+            ("workers", self.workers),
+            ("pending_queue", Some(self.pending_queue)),
+            ("channel_capacity", self.channel_capacity),
+            ("store_cache", Some(self.store_cache)),
+        ];
+        for (key, value) in positive {
+            if value == Some(0) {
+                return Err(anyhow::anyhow!("{key} must be greater than zero"));
+            }
+        }
+        if let Some(CoreList(cores)) = &self.worker_cores {
+            topology::validate_cores(cores)?;
+        }
+        if let Some(CoreList(cores)) = &self.reader_cores {
+            topology::validate_cores(cores)?;
+        }
+        if self.assignment == Strategy::Static && self.assignment_map.is_none() {
+            return Err(anyhow::anyhow!(
+                "the static assignment needs an assignment_map"
+            ));
+        }
+        if self.fee_tiers.is_some() && self.fees.is_none() {
+            return Err(anyhow::anyhow!("fee_tiers needs fees"));
+        }
+        Ok(())
+    }
+
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+}
+
+/// optional values kept in their command line spelling i.e. `2-7` or `30s`
+mod text {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    pub fn serialize<T: Display, S: Serializer>(
+        value: &Option<T>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => serializer.collect_str(value),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        T: FromStr<Err = String>,
+        D: Deserializer<'de>,
+    {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map(Some).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn parse(text: &str) -> anyhow::Result<Config> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        write!(file, "{text}")?;
+        Config::load(file.path())
+    }
+
+    #[test]
+    fn missing_keys_keep_their_defaults() {
+        let config = parse(
+            "workers = 3\nworker_cores = \"0-2\"\ndispute_horizon = \"30000tx\"\nassignment = \"consistent-hash\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.workers, Some(3));
+        assert_eq!(config.worker_cores, Some(CoreList(vec![0, 1, 2])));
+        assert_eq!(config.dispute_horizon, Some(Horizon::Transactions(30_000)));
+        assert_eq!(config.assignment, Strategy::ConsistentHash);
+        assert_eq!(config.batch_size, TX_CHUNK_SIZE);
+        assert_eq!(config.pending_queue, PENDING_QUEUE_SIZE);
+
+        let again: Config = toml::from_str(&config.to_toml().unwrap()).unwrap();
+        assert_eq!(again.to_toml().unwrap(), config.to_toml().unwrap());
+    }
+
+    #[test]
+    fn unknown_keys_and_bad_values_are_errors() {
+        let error = parse("worker = 3\n").err().unwrap();
+        assert!(error.to_string().contains("unknown field"), "{error}");
+        assert!(parse("dispute_horizon = \"0\"\n").is_err());
+        assert!(parse("dispute_horizon = \"30s\"\n").is_err());
+        assert!(parse("format = \"xml\"\n").is_err());
+    }
+
+    #[test]
+    fn validate_rejects_what_the_engine_can_not_run() {
+        assert!(Config::default().validate().is_ok());
+        for text in [
+            "batch_size = 0\n",
+            "follow_poll_ms = 0\n",
+            "pending_queue = 0\n",
+            "assignment = \"static\"\n",
+            "fee_tiers = \"tiers.csv\"\n",
+        ] {
+            assert!(parse(text).unwrap().validate().is_err(), "{text}");
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use crate::output::{AccountOutput, format_amount};
+use crate::transaction::ClientId;
+use arrow::array::{Array, ArrayRef, AsArray, RecordBatch};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, UInt64Type};
+use arrow::error::ArrowError;
+use arrow::ipc::reader::FileReader;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+// note: leading bytes of an arrow ipc file and of a parquet file
+const ARROW_MAGIC: &[u8] = b"ARROW1";
+const PARQUET_MAGIC: &[u8] = b"PAR1";
+
+/// one account row as written by `AccountWriter`, credit columns are optional
+#[derive(Deserialize)]
+struct Row {
+    client: ClientId,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+}
+
+impl From<Row> for AccountOutput {
+    fn from(row: Row) -> Self {
+        AccountOutput {
+            client: row.client,
+            available: row.available,
+            held: row.held,
+            total: row.total,
+            locked: row.locked,
+            credit_limit: Decimal::ZERO,
+            credit_used: Decimal::ZERO,
+        }
+    }
+}
+
+/// the accounts of an output file by client, the format is told from the
+/// content: csv, jsonl, an arrow ipc file or a parquet file
+pub fn read_accounts(path: &Path) -> anyhow::Result<BTreeMap<ClientId, AccountOutput>> {
+    let mut reader = BufReader::new(
+        File::open(path).map_err(|e| anyhow::anyhow!("failed to open {}: {e}", path.display()))?,
+    );
+    let head = reader.fill_buf()?.to_vec();
+    let rows = if head.starts_with(ARROW_MAGIC) {
+        read_batches(FileReader::try_new(reader, None)?)?
+    } else if head.starts_with(PARQUET_MAGIC) {
+        // note: the parquet reader seeks to the footer itself, so it gets the file
+        read_batches(ParquetRecordBatchReaderBuilder::try_new(reader.into_inner())?.build()?)?
+    } else if head.first() == Some(&b'{') {
+        reader
+            .lines()
+            .filter(|line| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+            .map(|line| Ok(serde_json::from_str::<Row>(&line?)?.into()))
+            .collect::<anyhow::Result<Vec<AccountOutput>>>()?
+    } else {
+        csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader)
+            .deserialize::<Row>()
+            .map(|row| Ok(row?.into()))
+            .collect::<anyhow::Result<Vec<AccountOutput>>>()?
+    };
+
+    let mut accounts = BTreeMap::new();
+    for account in rows {
+        let client = account.client;
+        if accounts.insert(client, account).is_some() {
+            return Err(anyhow::anyhow!(
+                "client {client} is in {} more than once",
+                path.display()
+            ));
+        }
+    }
+    Ok(accounts)
+}
+
+fn read_batches(
+    batches: impl Iterator<Item = Result<RecordBatch, ArrowError>>,
+) -> anyhow::Result<Vec<AccountOutput>> {
+    let mut accounts = vec![];
+    for batch in batches {
+        let batch = batch?;
+        let clients = column(&batch, "client", &DataType::UInt64)?;
+        let clients = clients.as_primitive::<UInt64Type>();
+        let locked = column(&batch, "locked", &DataType::Boolean)?;
+        let locked = locked.as_boolean();
+        // note: amounts may be strings as in the csv or decimals, both go through text
+        let available = column(&batch, "available", &DataType::Utf8)?;
+        let held = column(&batch, "held", &DataType::Utf8)?;
+        let total = column(&batch, "total", &DataType::Utf8)?;
+        let (available, held, total) = (
+            available.as_string::<i32>(),
+            held.as_string::<i32>(),
+            total.as_string::<i32>(),
+        );
+        for i in 0..batch.num_rows() {
+            accounts.push(AccountOutput {
+                client: clients.value(i),
+                available: Decimal::from_str(available.value(i))?,
+                held: Decimal::from_str(held.value(i))?,
+                total: Decimal::from_str(total.value(i))?,
+                locked: locked.is_valid(i) && locked.value(i),
+                credit_limit: Decimal::ZERO,
+                credit_used: Decimal::ZERO,
+            });
+        }
+    }
+    Ok(accounts)
+}
+
+fn column(batch: &RecordBatch, name: &str, to: &DataType) -> anyhow::Result<ArrayRef> {
+    let column = batch
+        .column_by_name(name)
+        .ok_or_else(|| anyhow::anyhow!("no {name} column"))?;
+    Ok(cast(column, to)?)
+}
+
+/// one value that differs between the two files
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub left: String,
+    pub right: String,
+    /// right minus left, amounts only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delta: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountChange {
+    pub client: ClientId,
+    pub fields: Vec<FieldChange>,
+}
+
+/// how the right file differs from the left one
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct DiffReport {
+    pub left_accounts: usize,
+    pub right_accounts: usize,
+    pub unchanged: usize,
+    /// clients only in the right file
+    pub added: Vec<ClientId>,
+    /// clients only in the left file
+    pub removed: Vec<ClientId>,
+    pub changed: Vec<AccountChange>,
+}
+
+impl DiffReport {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// one line per added, removed or changed client, then the counts
+    pub fn write_text<W: Write>(&self, mut out: W) -> anyhow::Result<()> {
+        for client in &self.removed {
+            writeln!(out, "- {client}")?;
+        }
+        for client in &self.added {
+            writeln!(out, "+ {client}")?;
+        }
+        for change in &self.changed {
+            let fields: Vec<String> = change
+                .fields
+                .iter()
+                .map(|field| match &field.delta {
+                    Some(delta) => {
+                        format!(
+                            "{} {} -> {} ({delta})",
+                            field.field, field.left, field.right
+                        )
+                    }
+                    None => format!("{} {} -> {}", field.field, field.left, field.right),
+                })
+                .collect();
+            writeln!(out, "~ {} {}", change.client, fields.join(", "))?;
+        }
+        writeln!(
+            out,
+            "{} added, {} removed, {} changed, {} unchanged",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len(),
+            self.unchanged
+        )?;
+        Ok(())
+    }
+}
+
+/// compares the accounts client by client, amounts are compared as exact
+/// decimals so `1.5` and `1.5000` are the same
+pub fn diff(
+    left: &BTreeMap<ClientId, AccountOutput>,
+    right: &BTreeMap<ClientId, AccountOutput>,
+) -> DiffReport {
+    let mut report = DiffReport {
+        left_accounts: left.len(),
+        right_accounts: right.len(),
+        ..DiffReport::default()
+    };
+    report.removed = left
+        .keys()
+        .filter(|client| !right.contains_key(client))
+        .copied()
+        .collect();
+    for (client, after) in right {
+        let Some(before) = left.get(client) else {
+            report.added.push(*client);
+            continue;
+        };
+        let mut fields = vec![];
+        let amounts = [
+            ("available", before.available, after.available),
+            ("held", before.held, after.held),
+            ("total", before.total, after.total),
+        ];
+        for (field, left, right) in amounts {
+            if left != right {
+                fields.push(FieldChange {
+                    field,
+                    left: format_amount(left),
+                    right: format_amount(right),
+                    delta: Some(format!("{:+.4}", right - left)),
+                });
+            }
+        }
+        if before.locked != after.locked {
+            fields.push(FieldChange {
+                field: "locked",
+                left: before.locked.to_string(),
+                right: after.locked.to_string(),
+                delta: None,
+            });
+        }
+        match fields.is_empty() {
+            true => report.unchanged += 1,
+            false => report.changed.push(AccountChange {
+                client: *client,
+                fields,
+            }),
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{AccountWriter, OutputFormat};
+    use tempfile::NamedTempFile;
+
+    fn account(client: ClientId, available: &str, locked: bool) -> AccountOutput {
+        let available = Decimal::from_str(available).unwrap();
+        AccountOutput {
+            client,
+            available,
+            held: Decimal::ZERO,
+            total: available,
+            locked,
+            credit_limit: Decimal::ZERO,
+            credit_used: Decimal::ZERO,
+        }
+    }
+
+    fn written(accounts: &[AccountOutput], format: OutputFormat) -> NamedTempFile {
+        let file = NamedTempFile::new().unwrap();
+        let mut writer = AccountWriter::with_format(file.reopen().unwrap(), format);
+        writer.write(accounts).unwrap();
+        writer.finish().unwrap();
+        file
+    }
+
+    #[test]
+    fn every_output_format_reads_back() {
+        let accounts = [account(1, "10.5", false), account(2, "1.2345", true)];
+        let csv = read_accounts(written(&accounts, OutputFormat::Csv).path()).unwrap();
+        for format in [
+            OutputFormat::Jsonl,
+            OutputFormat::Arrow,
+            OutputFormat::Parquet,
+        ] {
+            let other = read_accounts(written(&accounts, format).path()).unwrap();
+            let report = diff(&csv, &other);
+            assert!(report.is_empty(), "{format:?}");
+            assert_eq!(report.unchanged, 2);
+        }
+    }
+
+    #[test]
+    fn changes_are_reported_per_field() {
+        let left = [account(1, "10", false), account(2, "5", false)];
+        let right = [account(2, "4.5", true), account(3, "1", false)];
+        let report = diff(
+            &read_accounts(written(&left, OutputFormat::Csv).path()).unwrap(),
+            &read_accounts(written(&right, OutputFormat::Parquet).path()).unwrap(),
+        );
+        assert_eq!(report.removed, vec![1]);
+        assert_eq!(report.added, vec![3]);
+        let fields: Vec<_> = report.changed[0].fields.iter().map(|f| f.field).collect();
+        assert_eq!(fields, ["available", "total", "locked"]);
+        assert_eq!(
+            report.changed[0].fields[0].delta.as_deref(),
+            Some("-0.5000")
+        );
+    }
+}
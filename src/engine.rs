@@ -1,57 +1,219 @@
+use crate::fees::Fees;
+use crate::ledger::JournalLog;
+use crate::limits::CreditLimits;
 use crate::output::AccountOutput;
-use crate::shard::Worker;
-use crate::transaction::Transaction;
-use crossbeam::channel::{Receiver, Sender, unbounded};
+use crate::reconcile::Reconciliation;
+use crate::retention::Horizon;
+use crate::shard::{DISPUTE_WINDOW_MILLISECONDS, IdleStrategy, PENDING_QUEUE_SIZE, Worker};
+use crate::statement::{Clients, StatementLog};
+use crate::store::FileStoreConfig;
+use crate::topology;
+use crate::transaction::TxBatch;
+use crossbeam::channel::{Receiver, Sender, bounded, unbounded};
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 use std::thread;
 
+#[derive(Debug, Clone)]
+pub struct EngineOptions {
+    /// one shard per worker thread
+    pub workers: usize,
+    /// pin each worker to a core, worker `i` gets `cores[i % cores.len()]`
+    pub pin: bool,
+    pub cores: Vec<usize>,
+    /// how long a withdrawal waits for a dispute to overtake it, zero applies
+    /// every transaction in arrival order
+    pub dispute_window_ms: u64,
+    pub idle: IdleStrategy,
+    /// withdrawals each shard holds back for their dispute window at once, a
+    /// full queue holds up the shard rather than dropping any
+    pub pending_queue: usize,
+    /// batches each shard channel holds before the reader waits, unbounded
+    /// by default
+    pub channel_capacity: Option<usize>,
+    /// clients whose full history is kept for statements, none by default
+    pub statements: Option<Arc<Clients>>,
+    /// keep every journal entry for the journal export
+    pub journal: bool,
+    /// credit lines accounts are opened with, admin `limit` rows still apply
+    pub credit_limits: Option<Arc<CreditLimits>>,
+    /// fee schedules accounts are opened with, no fees by default
+    pub fees: Option<Arc<Fees>>,
+    /// how long deposits and withdrawals are kept, forever by default
+    pub dispute_horizon: Option<Horizon>,
+    /// keeps accounts in an account file, in memory by default
+    pub store: Option<FileStoreConfig>,
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        EngineOptions {
+            workers: thread::available_parallelism().map_or(1, |n| n.get()),
+            pin: true,
+            cores: topology::available_cores(),
+            dispute_window_ms: DISPUTE_WINDOW_MILLISECONDS,
+            idle: IdleStrategy::Spin,
+            pending_queue: PENDING_QUEUE_SIZE,
+            channel_capacity: None,
+            statements: None,
+            journal: false,
+            credit_limits: None,
+            fees: None,
+            dispute_horizon: None,
+            store: None,
+        }
+    }
+}
+
 pub struct Engine {
-    receivers: Vec<Receiver<Vec<Transaction>>>,
+    receivers: Vec<Receiver<TxBatch>>,
+    options: EngineOptions,
     done: Arc<AtomicBool>,
+    statements: StatementLog,
+    journal: JournalLog,
+    reconciliation: Reconciliation,
 }
 
 impl Engine {
     pub fn new(
-        workers: usize,
+        options: EngineOptions,
         done: Arc<AtomicBool>,
-    ) -> anyhow::Result<(Self, Vec<Sender<Vec<Transaction>>>)> {
+    ) -> anyhow::Result<(Self, Vec<Sender<TxBatch>>)> {
+        if options.workers == 0 {
+            return Err(anyhow::anyhow!("the engine needs at least one worker"));
+        }
+        if options.pending_queue == 0 {
+            return Err(anyhow::anyhow!(
+                "the pending withdrawal queue needs room for one"
+            ));
+        }
+        if options.pin {
+            if options.cores.is_empty() {
+                return Err(anyhow::anyhow!("pinning is enabled but no cores are given"));
+            }
+            topology::validate_cores(&options.cores)?;
+        }
+
         let mut senders = vec![];
         let mut receivers = vec![];
-        for _ in 0..=workers {
-            let (tx, rx) = unbounded::<Vec<Transaction>>();
+        for _ in 0..options.workers {
+            let (tx, rx) = match options.channel_capacity {
+                Some(capacity) => bounded::<TxBatch>(capacity),
+                None => unbounded::<TxBatch>(),
+            };
             senders.push(tx);
             receivers.push(rx);
         }
-        let engine = Self { receivers, done };
+        let engine = Self {
+            receivers,
+            options,
+            done,
+            statements: StatementLog::default(),
+            journal: JournalLog::default(),
+            reconciliation: Reconciliation::default(),
+        };
         Ok((engine, senders))
     }
 
+    /// where the shards leave their statements, filled once they finish
+    pub fn statements(&self) -> StatementLog {
+        self.statements.clone()
+    }
+
+    /// where the shards leave their journals, filled once they finish
+    pub fn journal(&self) -> JournalLog {
+        self.journal.clone()
+    }
+
+    /// where the shards leave their totals, filled once they finish
+    pub fn reconciliation(&self) -> Reconciliation {
+        self.reconciliation.clone()
+    }
+
+    /// runs the shards to completion and returns every account ordered by client id
     pub fn run(self) -> anyhow::Result<Vec<AccountOutput>> {
-        let (tx, rx) = crossbeam::channel::unbounded::<AccountOutput>();
+        let mut results = vec![];
+        self.run_with(|_, accounts| {
+            results.extend(accounts);
+            Ok(())
+        })?;
+        results.sort_unstable_by_key(|account| account.client);
+        Ok(results)
+    }
+
+    /// runs the shards to completion, handing each shard's accounts to
+    /// `on_shard` as soon as that shard finishes
+    pub fn run_with<F>(self, mut on_shard: F) -> anyhow::Result<()>
+    where
+        F: FnMut(u16, Vec<AccountOutput>) -> anyhow::Result<()>,
+    {
+        let (tx, rx) = crossbeam::channel::unbounded::<(u16, Vec<AccountOutput>)>();
         let mut handles = vec![];
 
         for (id, receiver) in self.receivers.into_iter().enumerate() {
             let done = self.done.clone();
             let tx = tx.clone();
-            let handle = thread::Builder::new().name(id.to_string()).spawn(move || {
-                core_affinity::set_for_current(core_affinity::CoreId { id });
-                let mut worker = Worker::new(id as u16, receiver);
-                let output_accounts = worker.run(done);
-                for account in output_accounts {
-                    tx.send(account).ok();
-                }
-            })?;
+            let dispute_window_ms = self.options.dispute_window_ms;
+            let idle = self.options.idle;
+            let pending_queue = self.options.pending_queue;
+            let statements = self.options.statements.clone();
+            let log = self.statements.clone();
+            let journal = self.options.journal.then(|| self.journal.clone());
+            let credit_limits = self.options.credit_limits.clone();
+            let fees = self.options.fees.clone();
+            let dispute_horizon = self.options.dispute_horizon;
+            let store = self.options.store.clone();
+            let reconciliation = self.reconciliation.clone();
+            let core = match self.options.pin {
+                true => Some(self.options.cores[id % self.options.cores.len()]),
+                false => None,
+            };
+            let handle = thread::Builder::new().name(id.to_string()).spawn(
+                move || -> anyhow::Result<()> {
+                    if let Some(core) = core {
+                        topology::pin_current(core);
+                    }
+                    let mut worker = Worker::new(id as u16, receiver, dispute_window_ms, idle)
+                        .with_pending_queue(pending_queue);
+                    if let Some(clients) = statements {
+                        worker = worker.with_statements(clients, log);
+                    }
+                    if let Some(journal) = journal {
+                        worker = worker.with_journal(journal);
+                    }
+                    if let Some(limits) = credit_limits {
+                        worker = worker.with_credit_limits(limits);
+                    }
+                    if let Some(fees) = fees {
+                        worker = worker.with_fees(fees);
+                    }
+                    if let Some(horizon) = dispute_horizon {
+                        worker = worker.with_dispute_horizon(horizon);
+                    }
+                    if let Some(store) = store {
+                        worker = worker.with_store(store);
+                    }
+                    let output_accounts = worker.run(done)?;
+                    reconciliation.add(worker.totals().clone());
+                    tx.send((id as u16, output_accounts)).ok();
+                    Ok(())
+                },
+            )?;
             handles.push(handle);
         }
         drop(tx);
-        for handle in handles {
-            handle.join().ok();
+
+        let mut delivered = Ok(());
+        for (shard, accounts) in rx {
+            if delivered.is_ok() {
+                delivered = on_shard(shard, accounts);
+            }
         }
-        let mut results = vec![];
-        for account in rx {
-            results.push(account);
+        for handle in handles {
+            handle
+                .join()
+                .map_err(|_| anyhow::anyhow!("shard worker panicked"))??;
         }
-        Ok(results)
+        delivered
     }
 }
@@ -0,0 +1,135 @@
+use crate::transaction::ClientId;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+// tier used for clients missing from the tier file
+pub const DEFAULT_TIER: &str = "default";
+const BASIS_POINTS: i64 = 10_000;
+
+/// a flat fee plus basis points of the amount
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fee {
+    pub flat: Decimal,
+    pub bps: Decimal,
+}
+
+impl Fee {
+    pub fn on(&self, amount: Decimal) -> Decimal {
+        (self.flat + amount * self.bps / Decimal::from(BASIS_POINTS)).round_dp(4)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.flat.is_zero() && self.bps.is_zero()
+    }
+}
+
+/// what a client pays per transaction type
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeSchedule {
+    pub deposit: Fee,
+    pub withdraw: Fee,
+    pub chargeback: Fee,
+}
+
+impl FeeSchedule {
+    pub fn is_zero(&self) -> bool {
+        self.deposit.is_zero() && self.withdraw.is_zero() && self.chargeback.is_zero()
+    }
+}
+
+/// fee schedules per tier and the tier of every client
+#[derive(Debug, Default)]
+pub struct Fees {
+    tiers: HashMap<String, FeeSchedule>,
+    clients: HashMap<ClientId, String>,
+}
+
+#[derive(Deserialize)]
+struct ScheduleRow {
+    tier: String,
+    #[serde(rename = "type")]
+    kind: String,
+    flat: String,
+    bps: String,
+}
+
+#[derive(Deserialize)]
+struct TierRow {
+    client: ClientId,
+    tier: String,
+}
+
+fn parse_amount(value: &str, what: &str, tier: &str) -> anyhow::Result<Decimal> {
+    let amount = Decimal::from_str(value)
+        .map_err(|e| anyhow::anyhow!("bad {} {:?} in fee tier {}: {}", what, value, tier, e))?;
+    if amount.is_sign_negative() {
+        return Err(anyhow::anyhow!(
+            "negative {} {} in fee tier {}",
+            what,
+            amount,
+            tier
+        ));
+    }
+    Ok(amount)
+}
+
+impl Fees {
+    /// loads a `tier,type,flat,bps` schedule and optionally a `client,tier` csv,
+    /// clients without a tier pay the `default` tier, or nothing when there is none
+    pub fn load(schedule: &Path, tiers: Option<&Path>) -> anyhow::Result<Self> {
+        let mut fees = Fees::default();
+
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(schedule)?;
+        for row in reader.deserialize() {
+            let row: ScheduleRow = row?;
+            let fee = Fee {
+                flat: parse_amount(&row.flat, "flat fee", &row.tier)?,
+                bps: parse_amount(&row.bps, "basis points", &row.tier)?,
+            };
+            let schedule = fees.tiers.entry(row.tier.clone()).or_default();
+            match row.kind.as_str() {
+                "deposit" => schedule.deposit = fee,
+                "withdraw" => schedule.withdraw = fee,
+                "chargeback" => schedule.chargeback = fee,
+                other => {
+                    return Err(anyhow::anyhow!(
+                        "unknown fee type {} in fee tier {}, expected deposit, withdraw or chargeback",
+                        other,
+                        row.tier
+                    ));
+                }
+            }
+        }
+
+        if let Some(tiers) = tiers {
+            let mut reader = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_path(tiers)?;
+            for row in reader.deserialize() {
+                let row: TierRow = row?;
+                if !fees.tiers.contains_key(&row.tier) {
+                    return Err(anyhow::anyhow!(
+                        "client {} is in fee tier {} which has no schedule",
+                        row.client,
+                        row.tier
+                    ));
+                }
+                fees.clients.insert(row.client, row.tier);
+            }
+        }
+        Ok(fees)
+    }
+
+    pub fn schedule(&self, client: ClientId) -> FeeSchedule {
+        let tier = self
+            .clients
+            .get(&client)
+            .map_or(DEFAULT_TIER, String::as_str);
+        self.tiers.get(tier).copied().unwrap_or_default()
+    }
+}
@@ -0,0 +1,308 @@
+use crate::assignment::ShardAssignment;
+use crate::io::{Compression, FileStats, input_schema, parse_chunk, send_sharded, sent};
+use crate::transaction::TxBatch;
+use arrow::datatypes::Schema;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+pub const FOLLOW_POLL_MILLISECONDS: u64 = 100;
+// most bytes taken from the file between two sends to the shards
+const READ_BYTES: usize = 1024 * 1024;
+
+/// where a followed file is picked up again, the byte just after the last row
+/// sent to the shards
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub path: PathBuf,
+    /// tells a rotated file from the one the offset belongs to
+    pub inode: u64,
+    pub offset: u64,
+}
+
+impl Checkpoint {
+    /// `None` when no checkpoint was written yet
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes).map_err(|e| {
+                anyhow::anyhow!("bad checkpoint {}: {e}", path.display())
+            })?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// replaces the checkpoint in one rename so a crash never leaves half of one
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// the file being followed and how much of it has been consumed
+struct Followed {
+    file: File,
+    inode: u64,
+    offset: u64,
+    // note: none until the header line has been read
+    schema: Option<Arc<Schema>>,
+    // note: bytes read past `offset` that do not make a full row yet
+    pending: Vec<u8>,
+}
+
+impl Followed {
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        let inode = file.metadata()?.ino();
+        Ok(Followed {
+            file,
+            inode,
+            offset: 0,
+            schema: None,
+            pending: vec![],
+        })
+    }
+
+    /// picks up at the checkpoint when it belongs to this very file and the
+    /// file still reaches the offset, otherwise starts from the top
+    fn resume(path: &Path, from: Option<&Checkpoint>) -> anyhow::Result<Self> {
+        let mut followed = Followed::open(path)?;
+        let from = match from {
+            Some(from) if from.offset > 0 => from,
+            _ => return Ok(followed),
+        };
+        if from.inode != followed.inode {
+            warn!(file = %path.display(), "file was rotated since the checkpoint, reading it from the start");
+            return Ok(followed);
+        }
+        if followed.file.metadata()?.len() < from.offset {
+            warn!(file = %path.display(), "file was truncated since the checkpoint, reading it from the start");
+            return Ok(followed);
+        }
+        let mut header = vec![];
+        BufReader::new(&followed.file).read_until(b'\n', &mut header)?;
+        followed.schema = Some(input_schema(&String::from_utf8_lossy(&header)));
+        followed.file.seek(SeekFrom::Start(from.offset))?;
+        followed.offset = from.offset;
+        info!(file = %path.display(), offset = from.offset, "resuming from the checkpoint");
+        Ok(followed)
+    }
+
+    /// the complete rows read so far, the header is taken off first
+    fn rows(&mut self) -> Option<(Arc<Schema>, Vec<u8>)> {
+        if self.schema.is_none() {
+            let newline = self.pending.iter().position(|&b| b == b'\n')?;
+            let header: Vec<u8> = self.pending.drain(..=newline).collect();
+            self.schema = Some(input_schema(&String::from_utf8_lossy(&header)));
+            self.offset += header.len() as u64;
+        }
+        let end = self.pending.iter().rposition(|&b| b == b'\n')? + 1;
+        let rows: Vec<u8> = self.pending.drain(..end).collect();
+        self.offset += rows.len() as u64;
+        self.schema.clone().map(|schema| (schema, rows))
+    }
+}
+
+/// reads `path` as it grows, like `tail -f`, until `stop` is set
+///
+/// a file replaced under the same name is read to its end before the new one
+/// is opened, a file that shrinks is read again from the start
+pub fn follow(
+    path: &Path,
+    from: Option<&Checkpoint>,
+    senders: &[crossbeam::channel::Sender<TxBatch>],
+    assignment: &dyn ShardAssignment,
+    batch_size: usize,
+    poll: Duration,
+    stop: &AtomicBool,
+) -> anyhow::Result<FileStats> {
+    if Compression::detect(path)? != Compression::None {
+        return Err(anyhow::anyhow!(
+            "{} is compressed and can not be followed",
+            path.display()
+        ));
+    }
+    let source = path.to_string_lossy();
+    let started = Instant::now();
+    let mut stats = FileStats::new(&source, 0);
+    let mut followed = Followed::resume(path, from)?;
+    let mut buf = vec![0u8; READ_BYTES];
+    info!(file = %source, offset = followed.offset, "following file");
+
+    let mut send = |followed: &mut Followed| -> anyhow::Result<()> {
+        if let Some((schema, rows)) = followed.rows() {
+            let (sharded, parsed) = parse_chunk(
+                &source,
+                &rows,
+                senders.len(),
+                assignment,
+                schema,
+                batch_size,
+            )?;
+            stats.rows += parsed;
+            stats.transactions += sent(&sharded);
+            stats.bytes += rows.len() as u64;
+            send_sharded(senders, sharded);
+        }
+        Ok(())
+    };
+
+    while !stop.load(Relaxed) {
+        let read = followed.file.read(&mut buf)?;
+        if read > 0 {
+            followed.pending.extend_from_slice(&buf[..read]);
+            send(&mut followed)?;
+            continue;
+        }
+
+        // note: the name may be missing for a moment while the file is rotated
+        let current = match fs::metadata(path) {
+            Ok(current) => current,
+            Err(_) => {
+                thread::sleep(poll);
+                continue;
+            }
+        };
+        if current.ino() != followed.inode {
+            // note: the last row of a rotated file may lack its newline
+            if !followed.pending.is_empty() {
+                followed.pending.push(b'\n');
+                send(&mut followed)?;
+            }
+            info!(file = %source, "file rotated, following the new one");
+            followed = Followed::open(path)?;
+        } else if current.len() < followed.offset + followed.pending.len() as u64 {
+            warn!(file = %source, "file truncated, reading it from the start");
+            followed = Followed::open(path)?;
+        } else {
+            thread::sleep(poll);
+        }
+    }
+    info!(file = %source, offset = followed.offset, "stop requested, intake stopped");
+
+    stats.resume = Some(Checkpoint {
+        path: path.to_path_buf(),
+        inode: followed.inode,
+        offset: followed.offset,
+    });
+    stats.elapsed = started.elapsed();
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assignment::Modulo;
+    use std::fs::OpenOptions;
+
+    const HEADER: &str = "type,client,tx,amount\n";
+
+    #[test]
+    fn checkpoint_round_trips_and_may_be_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("checkpoint.json");
+        assert_eq!(Checkpoint::load(&path).unwrap(), None);
+        let checkpoint = Checkpoint {
+            path: dir.path().join("drop.csv"),
+            inode: 7,
+            offset: 42,
+        };
+        checkpoint.save(&path).unwrap();
+        assert_eq!(Checkpoint::load(&path).unwrap(), Some(checkpoint));
+        fs::write(&path, "{").unwrap();
+        assert!(Checkpoint::load(&path).is_err());
+    }
+
+    #[test]
+    fn rows_hold_back_a_partial_row() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut followed = Followed::open(file.path()).unwrap();
+        followed
+            .pending
+            .extend_from_slice(b"type,client,tx,amount\ndeposit,1,1,1.0\ndepo");
+        let (_, rows) = followed.rows().unwrap();
+        assert_eq!(rows, b"deposit,1,1,1.0\n");
+        assert_eq!(followed.pending, b"depo");
+        assert_eq!(followed.offset, (HEADER.len() + rows.len()) as u64);
+        assert!(followed.rows().is_none());
+    }
+
+    #[test]
+    fn resume_only_trusts_a_checkpoint_of_the_same_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "{HEADER}deposit,1,1,1.0").unwrap();
+        let len = file.as_file().metadata().unwrap().len();
+        let inode = file.as_file().metadata().unwrap().ino();
+        let checkpoint = |inode, offset| Checkpoint {
+            path: file.path().to_path_buf(),
+            inode,
+            offset,
+        };
+
+        let resumed = Followed::resume(file.path(), Some(&checkpoint(inode, len))).unwrap();
+        assert_eq!(resumed.offset, len);
+        assert!(resumed.schema.is_some());
+        for stale in [checkpoint(inode + 1, len), checkpoint(inode, len + 1)] {
+            let resumed = Followed::resume(file.path(), Some(&stale)).unwrap();
+            assert_eq!(resumed.offset, 0);
+            assert!(resumed.schema.is_none());
+        }
+    }
+
+    #[test]
+    fn rotated_file_is_read_to_its_end_before_the_new_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("drop.csv");
+        fs::write(&path, format!("{HEADER}deposit,1,1,1.0\n")).unwrap();
+
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        let stop = Arc::new(AtomicBool::new(false));
+        let follower = {
+            let (path, stop) = (path.clone(), stop.clone());
+            thread::spawn(move || {
+                let poll = Duration::from_millis(5);
+                follow(&path, None, &[sender], &Modulo::new(1), 16, poll, &stop)
+            })
+        };
+        let mut ids = vec![];
+        let mut receive = |count: usize| {
+            while ids.len() < count {
+                let batch: TxBatch = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+                ids.extend(batch.txs.iter().map(|transaction| transaction.tx().id));
+            }
+            ids.clone()
+        };
+        assert_eq!(receive(1), [1]);
+
+        // note: the last row of the old file has no newline
+        let mut old = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(old, "deposit,1,2,2.0").unwrap();
+        fs::rename(&path, dir.path().join("drop.csv.1")).unwrap();
+        fs::write(&path, format!("{HEADER}deposit,1,3,3.0\n")).unwrap();
+        assert_eq!(receive(3), [1, 2, 3]);
+
+        stop.store(true, Relaxed);
+        let stats = follower.join().unwrap().unwrap();
+        let resume = stats.resume.unwrap();
+        let current = fs::metadata(&path).unwrap();
+        assert_eq!(
+            (resume.inode, resume.offset),
+            (current.ino(), current.len())
+        );
+        assert_eq!(stats.transactions, 3);
+        // note: nothing was sent twice
+        assert!(receiver.try_recv().is_err());
+    }
+}
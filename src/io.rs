@@ -1,41 +1,232 @@
-use crate::transaction::{Transaction, Tx};
-use arrow::array::{Array, StringArray, UInt16Array, UInt32Array};
+use crate::assignment::{Modulo, ShardAssignment};
+use crate::follow::{self, Checkpoint};
+use crate::metrics;
+use crate::topology;
+use crate::transaction::{ClientId, Transaction, Tx, TxBatch};
+use arrow::array::{Array, StringArray, UInt64Array};
 use arrow::csv::ReaderBuilder;
 use arrow::datatypes::{DataType, Field, Schema};
 use arrow::error::ArrowError;
 use arrow::record_batch::RecordBatch;
 use arrow_csv::reader::Decoder;
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use clap::ValueEnum;
 use futures::Stream;
 use futures::TryStreamExt;
 use futures::ready;
 use lazy_static::lazy_static;
+use memmap2::Mmap;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::ops::{ControlFlow, Range};
+use std::path::Path;
 use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::task::Poll;
-use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncBufRead;
 use tokio::runtime::Runtime;
+use tracing::{error, info, warn};
 
-struct UpstreamPartnerConnection {
-    id: u16,
-    sequenced_files: Vec<u64>,
-}
-
-const TX_CHUNK_SIZE: usize = 5000;
+/// rows the reader decodes and sends to the shards at a time
+pub const TX_CHUNK_SIZE: usize = 5000;
+// files at least this large are split and parsed on the rayon pool
+const PARALLEL_PARSE_MIN_BYTES: u64 = 64 * 1024 * 1024;
+const PARALLEL_CHUNK_BYTES: usize = 16 * 1024 * 1024;
 
 lazy_static! {
     static ref CSV_SCHEMA_INPUT: Schema = Schema::new(vec![
         Field::new("type", DataType::Utf8, false),
-        Field::new("client", DataType::UInt16, false),
-        Field::new("tx", DataType::UInt32, false),
+        Field::new("client", DataType::UInt64, false),
+        Field::new("tx", DataType::UInt64, false),
+        Field::new("amount", DataType::Utf8, true),
+    ]);
+    // note: the optional trailing column rows are merged on across files
+    static ref CSV_SCHEMA_TIMESTAMPED: Schema = Schema::new(vec![
+        Field::new("type", DataType::Utf8, false),
+        Field::new("client", DataType::UInt64, false),
+        Field::new("tx", DataType::UInt64, false),
         Field::new("amount", DataType::Utf8, true),
+        Field::new("timestamp", DataType::UInt64, false),
     ]);
 }
 
+/// how rows of several input files are interleaved
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InputOrder {
+    /// one file after another in the given order, a client's rows in a later
+    /// file come after its rows in an earlier one
+    Sequential,
+    /// rows of every file merged on their `timestamp` column, ties go to the
+    /// earlier file
+    Timestamp,
+    /// files read at the same time, only safe when no client is in two files
+    Concurrent,
+}
+
+/// what the reader made of one input file
+#[derive(Debug, Clone, Default)]
+pub struct FileStats {
+    pub path: String,
+    pub bytes: u64,
+    pub rows: u64,
+    /// well formed rows, the rest were skipped as malformed
+    pub transactions: u64,
+    /// rows with an earlier timestamp than the row before them
+    pub out_of_order: u64,
+    pub elapsed: Duration,
+    /// where a followed file picks up again
+    pub resume: Option<Checkpoint>,
+}
+
+impl FileStats {
+    pub(crate) fn new(path: &str, bytes: u64) -> Self {
+        FileStats {
+            path: path.to_string(),
+            bytes,
+            ..FileStats::default()
+        }
+    }
+
+    pub fn skipped(&self) -> u64 {
+        self.rows - self.transactions
+    }
+}
+
+/// the columns of a file from its header, a `timestamp` column is optional
+pub(crate) fn input_schema(header: &str) -> Arc<Schema> {
+    match header.split(',').any(|column| column.trim() == "timestamp") {
+        true => Arc::new(CSV_SCHEMA_TIMESTAMPED.clone()),
+        false => Arc::new(CSV_SCHEMA_INPUT.clone()),
+    }
+}
+
+fn read_header(path: &Path) -> anyhow::Result<String> {
+    let mut header = String::new();
+    BufReader::new(open_decompressed(path)?).read_line(&mut header)?;
+    Ok(header)
+}
+
+/// expands glob patterns such as `drops/2026-10-*.csv`, matches of one
+/// pattern are in lexical order and a file given twice is read once
+pub fn expand_inputs(patterns: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut inputs: Vec<String> = vec![];
+    for pattern in patterns {
+        let matches = match pattern.contains(['*', '?', '[']) {
+            true => glob::glob(pattern)?
+                .map(|path| path.map(|path| path.to_string_lossy().into_owned()))
+                .collect::<Result<Vec<_>, _>>()?,
+            false => vec![pattern.clone()],
+        };
+        if matches.is_empty() {
+            return Err(anyhow::anyhow!("no input file matches {}", pattern));
+        }
+        for path in matches {
+            if inputs.contains(&path) {
+                warn!(file = %path, "input given more than once, reading it once");
+                continue;
+            }
+            inputs.push(path);
+        }
+    }
+    Ok(inputs)
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// the file extension wins, otherwise we sniff the magic bytes so renamed
+    /// partner drops are still decompressed
+    pub fn detect(path: &Path) -> std::io::Result<Self> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") | Some("gzip") => return Ok(Compression::Gzip),
+            Some("zst") | Some("zstd") => return Ok(Compression::Zstd),
+            _ => {}
+        }
+
+        let mut magic = [0u8; 4];
+        let mut file = File::open(path)?;
+        let mut read = 0;
+        while read < magic.len() {
+            match file.read(&mut magic[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+
+        if read >= GZIP_MAGIC.len() && magic[..GZIP_MAGIC.len()] == GZIP_MAGIC {
+            Ok(Compression::Gzip)
+        } else if read == ZSTD_MAGIC.len() && magic == ZSTD_MAGIC {
+            Ok(Compression::Zstd)
+        } else {
+            Ok(Compression::None)
+        }
+    }
+}
+
+/// blocking reader over a possibly compressed file, used for schema inference
+pub fn open_decompressed(path: &Path) -> anyhow::Result<Box<dyn Read>> {
+    let file = BufReader::new(File::open(path)?);
+    let reader: Box<dyn Read> = match Compression::detect(path)? {
+        Compression::None => Box::new(file),
+        Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(file)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(file)?),
+    };
+    Ok(reader)
+}
+
+async fn open_async_decompressed(
+    path: &Path,
+) -> anyhow::Result<Box<dyn AsyncBufRead + Unpin + Send>> {
+    let compression = Compression::detect(path)?;
+    let file = tokio::io::BufReader::new(tokio::fs::File::open(path).await?);
+    let reader: Box<dyn AsyncBufRead + Unpin + Send> = match compression {
+        Compression::None => Box::new(file),
+        Compression::Gzip => {
+            let mut decoder = GzipDecoder::new(file);
+            // note: partners concatenate gzip members when they append to a drop
+            decoder.multiple_members(true);
+            Box::new(tokio::io::BufReader::new(decoder))
+        }
+        Compression::Zstd => {
+            let mut decoder = ZstdDecoder::new(file);
+            decoder.multiple_members(true);
+            Box::new(tokio::io::BufReader::new(decoder))
+        }
+    };
+    Ok(reader)
+}
+
 pub struct ConcurrentAsyncFileDescriptorReader {
     rt: Runtime,
-    senders: Vec<crossbeam::channel::Sender<Vec<Transaction>>>,
+    senders: Vec<crossbeam::channel::Sender<TxBatch>>,
+    assignment: Arc<dyn ShardAssignment>,
+    // note: the global rayon pool is used when the reader is not pinned
+    pool: Option<Arc<ThreadPool>>,
+    // note: once set no further rows are sent to the shards
+    stop: Arc<AtomicBool>,
+    batch_size: usize,
+    order: InputOrder,
+    // note: the poll interval and where to pick up when following a file
+    follow: Option<(Duration, Option<Checkpoint>)>,
 }
 
 // note: decode_stream is pulled from here https://docs.rs/arrow-csv/latest/arrow_csv/reader/
@@ -63,97 +254,749 @@ fn decode_stream<R: AsyncBufRead + Unpin>(
     })
 }
 
+/// first pass over the inputs counting transactions per client id
+pub fn count_client_transactions(paths: &[String]) -> anyhow::Result<HashMap<ClientId, u64>> {
+    let mut counts = HashMap::new();
+    for path in paths {
+        let reader = ReaderBuilder::new(input_schema(&read_header(Path::new(path))?))
+            .with_header(true)
+            .with_batch_size(TX_CHUNK_SIZE)
+            .with_projection(vec![1])
+            .build(open_decompressed(Path::new(path))?)?;
+        for batch in reader {
+            let batch = batch?;
+            let clients = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<UInt64Array>()
+                .unwrap();
+            for client in clients.values() {
+                *counts.entry(*client).or_insert(0) += 1;
+            }
+        }
+    }
+    Ok(counts)
+}
+
+/// hands every transaction of the file to `visit` in file order, with its
+/// timestamp when the file has the column, until `visit` breaks
+pub fn read_transactions(
+    path: &str,
+    mut visit: impl FnMut(Option<u64>, Transaction) -> ControlFlow<()>,
+) -> anyhow::Result<()> {
+    let schema = input_schema(&read_header(Path::new(path))?);
+    let timestamped = schema.fields().len() == CSV_SCHEMA_TIMESTAMPED.fields().len();
+    let reader = ReaderBuilder::new(schema)
+        .with_header(true)
+        .with_batch_size(TX_CHUNK_SIZE)
+        .build(open_decompressed(Path::new(path))?)?;
+    for batch in reader {
+        let batch = batch?;
+        let timestamps = timestamped.then(|| {
+            batch
+                .column(4)
+                .as_any()
+                .downcast_ref::<UInt64Array>()
+                .unwrap()
+                .clone()
+        });
+        let mut flow = ControlFlow::Continue(());
+        decode_rows(path, &batch, |i, tx| {
+            if flow.is_continue() {
+                flow = visit(timestamps.as_ref().map(|t| t.value(i)), tx);
+            }
+        });
+        if flow.is_break() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// splits one decoded record batch into a batch of transactions per shard
+///
+/// rows keep their relative order within each shard so per-client chronology
+/// survives the fan out
+fn shard_batch(
+    source: &str,
+    batch: &RecordBatch,
+    shards: usize,
+    assignment: &dyn ShardAssignment,
+) -> Vec<Vec<Transaction>> {
+    let mut sharded: Vec<Vec<Transaction>> = (0..shards).map(|_| vec![]).collect();
+    decode_rows(source, batch, |_, tx| {
+        sharded[assignment.shard(tx.tx().client)].push(tx)
+    });
+    sharded
+}
+
+pub(crate) fn sent(sharded: &[Vec<Transaction>]) -> u64 {
+    sharded.iter().map(|txs| txs.len() as u64).sum()
+}
+
+/// hands every well formed row of the batch to `emit` with its row index,
+/// malformed rows are counted and skipped
+fn decode_rows(source: &str, batch: &RecordBatch, mut emit: impl FnMut(usize, Transaction)) {
+    // todo: is there a nicer way of doing this type casting / object tx serialization
+    //       in arrow?
+    //
+    // https://github.com/apache/arrow-rs/issues/1760
+
+    let types = batch
+        .column(0)
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .unwrap();
+    let clients = batch
+        .column(1)
+        .as_any()
+        .downcast_ref::<UInt64Array>()
+        .unwrap();
+    let ids = batch
+        .column(2)
+        .as_any()
+        .downcast_ref::<UInt64Array>()
+        .unwrap();
+    let amounts = batch
+        .column(3)
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .unwrap();
+
+    metrics::ROWS_PARSED.inc_by(batch.num_rows() as u64);
+
+    for i in 0..batch.num_rows() {
+        let intent = types.value(i);
+        let client = clients.value(i);
+        let id = ids.value(i);
+        let amount = if amounts.is_null(i) {
+            Decimal::ZERO
+        } else {
+            match Decimal::from_str(amounts.value(i).trim()) {
+                Ok(d) => d,
+                Err(_) => {
+                    metrics::ROWS_REJECTED
+                        .with_label_values(&["bad_amount"])
+                        .inc();
+                    warn!(
+                        file = source,
+                        client,
+                        tx = id,
+                        amount = amounts.value(i),
+                        "skipping bad amount"
+                    );
+                    continue;
+                }
+            }
+        };
+        let tx = Tx { client, id, amount };
+        let tx = match intent.trim() {
+            "deposit" => Transaction::Deposit(tx),
+            "withdraw" => Transaction::PendingWithdrawal(tx),
+            "dispute" => Transaction::Dispute(tx),
+            "resolve" => Transaction::Resolve(tx),
+            "chargeback" => Transaction::Chargeback(tx),
+            "limit" => Transaction::CreditLimit(tx),
+            _ => {
+                metrics::ROWS_REJECTED
+                    .with_label_values(&["unknown_type"])
+                    .inc();
+                warn!(
+                    file = source,
+                    client,
+                    tx = id,
+                    intent,
+                    "skipping unknown transaction type"
+                );
+                continue;
+            }
+        };
+        emit(i, tx);
+    }
+}
+
+pub(crate) fn send_sharded(
+    senders: &[crossbeam::channel::Sender<TxBatch>],
+    sharded: Vec<Vec<Transaction>>,
+) {
+    let ingested_at = Instant::now();
+    for (shard_idx, txs) in sharded.into_iter().enumerate() {
+        if txs.is_empty() {
+            continue;
+        }
+        if let Err(e) = senders[shard_idx].send(TxBatch { ingested_at, txs }) {
+            error!(shard = shard_idx, error = %e, "shard send failed");
+        }
+    }
+}
+
+/// decodes a headerless, newline aligned slice of the input file
+pub(crate) fn parse_chunk(
+    source: &str,
+    chunk: &[u8],
+    shards: usize,
+    assignment: &dyn ShardAssignment,
+    schema: Arc<Schema>,
+    batch_size: usize,
+) -> Result<(Vec<Vec<Transaction>>, u64), ArrowError> {
+    let mut decoder = ReaderBuilder::new(schema)
+        .with_header(false)
+        .with_batch_size(batch_size)
+        .build_decoder();
+
+    let mut sharded: Vec<Vec<Transaction>> = (0..shards).map(|_| vec![]).collect();
+    let mut rows = 0;
+    let mut rest = chunk;
+    loop {
+        let decoded = decoder.decode(rest)?;
+        rest = &rest[decoded..];
+        // note: a zero length decode either means the batch is full or, once
+        // rest is empty, delimits the final record
+        if decoded == 0 {
+            match decoder.flush()? {
+                Some(batch) => {
+                    rows += batch.num_rows() as u64;
+                    for (shard, txs) in shard_batch(source, &batch, shards, assignment)
+                        .into_iter()
+                        .enumerate()
+                    {
+                        sharded[shard].extend(txs);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+    Ok((sharded, rows))
+}
+
+/// splits the body of the file into byte ranges of roughly `chunk_size` that
+/// always end just after a newline
+fn newline_aligned_ranges(body: &[u8], chunk_size: usize) -> Vec<Range<usize>> {
+    let mut ranges = vec![];
+    let mut start = 0;
+    while start < body.len() {
+        let target = (start + chunk_size).min(body.len());
+        let end = match body[target..].iter().position(|&b| b == b'\n') {
+            Some(newline) => target + newline + 1,
+            None => body.len(),
+        };
+        ranges.push(start..end);
+        start = end;
+    }
+    ranges
+}
+
+/// parses one large uncompressed file on the rayon pool
+///
+/// the file is mmapped and cut into newline aligned chunks.  a wave of chunks is
+/// parsed in parallel and the results are released to the shards strictly in
+/// chunk sequence order, so every shard sees its clients' rows in file order
+fn consume_parallel(
+    path: &Path,
+    senders: &[crossbeam::channel::Sender<TxBatch>],
+    assignment: &dyn ShardAssignment,
+    batch_size: usize,
+    stop: &AtomicBool,
+) -> anyhow::Result<FileStats> {
+    let source = path.to_string_lossy();
+    let file = File::open(path)?;
+    // safety: drops are written once by the partner and never modified in place
+    let mmap = unsafe { Mmap::map(&file)? };
+    let mut stats = FileStats::new(&source, mmap.len() as u64);
+
+    let header_end = match mmap.iter().position(|&b| b == b'\n') {
+        Some(newline) => newline + 1,
+        None => return Ok(stats),
+    };
+    let schema = input_schema(&String::from_utf8_lossy(&mmap[..header_end]));
+    let body = &mmap[header_end..];
+    let ranges = newline_aligned_ranges(body, PARALLEL_CHUNK_BYTES);
+
+    let wave_size = rayon::current_num_threads().max(1);
+    info!(
+        file = %source,
+        chunks = ranges.len(),
+        threads = wave_size,
+        "parsing file in parallel"
+    );
+    for wave in ranges.chunks(wave_size) {
+        if stop.load(Relaxed) {
+            info!(file = %source, "stop requested, intake stopped");
+            break;
+        }
+        // note: an indexed parallel collect keeps the results in chunk sequence order
+        let parsed = wave
+            .par_iter()
+            .map(|range| {
+                let chunk = &body[range.clone()];
+                parse_chunk(
+                    &source,
+                    chunk,
+                    senders.len(),
+                    assignment,
+                    schema.clone(),
+                    batch_size,
+                )
+            })
+            .collect::<Result<Vec<_>, ArrowError>>()?;
+        for (sharded, rows) in parsed {
+            stats.rows += rows;
+            stats.transactions += sent(&sharded);
+            send_sharded(senders, sharded);
+        }
+    }
+    Ok(stats)
+}
+
+/// one input file of a timestamp merge, decoded a batch at a time
+struct MergeSource {
+    reader: arrow::csv::Reader<Box<dyn Read>>,
+    rows: VecDeque<(u64, Transaction)>,
+    last: u64,
+    stats: FileStats,
+}
+
+impl MergeSource {
+    fn open(path: &str, batch_size: usize) -> anyhow::Result<Self> {
+        let header = read_header(Path::new(path))?;
+        if !header.split(',').any(|column| column.trim() == "timestamp") {
+            return Err(anyhow::anyhow!(
+                "{} has no timestamp column to merge on",
+                path
+            ));
+        }
+        let reader = ReaderBuilder::new(input_schema(&header))
+            .with_header(true)
+            .with_batch_size(batch_size)
+            .build(open_decompressed(Path::new(path))?)?;
+        Ok(MergeSource {
+            reader,
+            rows: VecDeque::new(),
+            last: 0,
+            stats: FileStats::new(path, std::fs::metadata(path)?.len()),
+        })
+    }
+
+    /// the timestamp of the next row, decoding another batch when needed
+    fn peek(&mut self) -> anyhow::Result<Option<u64>> {
+        while self.rows.is_empty() {
+            let batch = match self.reader.next() {
+                Some(batch) => batch?,
+                None => return Ok(None),
+            };
+            let timestamps = batch
+                .column(4)
+                .as_any()
+                .downcast_ref::<UInt64Array>()
+                .unwrap();
+            self.stats.rows += batch.num_rows() as u64;
+            decode_rows(&self.stats.path, &batch, |i, tx| {
+                self.rows.push_back((timestamps.value(i), tx))
+            });
+        }
+        Ok(self.rows.front().map(|(timestamp, _)| *timestamp))
+    }
+
+    fn pop(&mut self) -> Option<(u64, Transaction)> {
+        let (timestamp, tx) = self.rows.pop_front()?;
+        if timestamp < self.last {
+            self.stats.out_of_order += 1;
+        }
+        self.last = timestamp;
+        self.stats.transactions += 1;
+        Some((timestamp, tx))
+    }
+}
+
+/// k way merge of the files on their timestamp column, ties go to the
+/// earlier file
+///
+/// each file is expected in timestamp order, rows that go back in time are
+/// passed on where they are and counted
+struct Merge {
+    sources: Vec<MergeSource>,
+    heap: BinaryHeap<Reverse<(u64, usize)>>,
+}
+
+impl Merge {
+    fn open(paths: &[String], batch_size: usize) -> anyhow::Result<Self> {
+        let mut sources = paths
+            .iter()
+            .map(|path| MergeSource::open(path, batch_size))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut heap = BinaryHeap::new();
+        for (idx, source) in sources.iter_mut().enumerate() {
+            if let Some(timestamp) = source.peek()? {
+                heap.push(Reverse((timestamp, idx)));
+            }
+        }
+        Ok(Merge { sources, heap })
+    }
+
+    /// the next row of every file together with its timestamp
+    fn next(&mut self) -> anyhow::Result<Option<(u64, Transaction)>> {
+        while let Some(Reverse((_, idx))) = self.heap.pop() {
+            let source = &mut self.sources[idx];
+            let row = source.pop();
+            if let Some(timestamp) = source.peek()? {
+                self.heap.push(Reverse((timestamp, idx)));
+            }
+            if row.is_some() {
+                return Ok(row);
+            }
+        }
+        Ok(None)
+    }
+
+    fn stats(self, elapsed: Duration) -> Vec<FileStats> {
+        self.sources
+            .into_iter()
+            .map(|source| FileStats {
+                elapsed,
+                ..source.stats
+            })
+            .collect()
+    }
+}
+
+/// hands the rows of every file to `visit` merged on their timestamp column,
+/// until `visit` breaks
+pub fn merge_transactions(
+    paths: &[String],
+    mut visit: impl FnMut(u64, Transaction) -> ControlFlow<()>,
+) -> anyhow::Result<()> {
+    let mut merge = Merge::open(paths, TX_CHUNK_SIZE)?;
+    while let Some((timestamp, tx)) = merge.next()? {
+        if visit(timestamp, tx).is_break() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// sends the merged rows of every file to the shards a batch at a time
+fn consume_merged(
+    paths: &[String],
+    senders: &[crossbeam::channel::Sender<TxBatch>],
+    assignment: &dyn ShardAssignment,
+    batch_size: usize,
+    stop: &AtomicBool,
+) -> anyhow::Result<Vec<FileStats>> {
+    let started = Instant::now();
+    let mut merge = Merge::open(paths, batch_size)?;
+
+    let mut sharded: Vec<Vec<Transaction>> = (0..senders.len()).map(|_| vec![]).collect();
+    let mut batched = 0;
+    while let Some((_, tx)) = merge.next()? {
+        sharded[assignment.shard(tx.tx().client)].push(tx);
+        batched += 1;
+        if batched == batch_size {
+            if stop.load(Relaxed) {
+                info!("stop requested, intake stopped");
+                break;
+            }
+            let full = (0..senders.len()).map(|_| vec![]).collect();
+            send_sharded(senders, std::mem::replace(&mut sharded, full));
+            batched = 0;
+        }
+    }
+    if !stop.load(Relaxed) {
+        send_sharded(senders, sharded);
+    }
+
+    Ok(merge.stats(started.elapsed()))
+}
+
 impl ConcurrentAsyncFileDescriptorReader {
-    pub fn new(senders: Vec<crossbeam::channel::Sender<Vec<Transaction>>>) -> Self {
+    pub fn new(senders: Vec<crossbeam::channel::Sender<TxBatch>>) -> Self {
         let rt = Runtime::new().expect("failed to create tokio runtime");
-        Self { rt, senders }
-    }
-
-    pub fn consume(&self, tx_csvs: Vec<String>) -> anyhow::Result<()> {
-        self.rt.block_on(async {
-            let mut handles = vec![];
-
-            // each partner has a sender
-            let senders = self.senders.clone();
-            for tx_csv in tx_csvs {
-                let senders = senders.clone();
-                let handle = tokio::spawn(async move {
-                    let file = tokio::fs::File::open(tx_csv).await?;
-                    let reader = tokio::io::BufReader::new(file);
-                    let decoder = ReaderBuilder::new(Arc::new(CSV_SCHEMA_INPUT.clone()))
-                        .with_header(true)
-                        .with_batch_size(TX_CHUNK_SIZE)
-                        .build_decoder();
-
-                    let mut stream = decode_stream(decoder, reader);
-
-                    while let Some(batch) = stream.try_next().await? {
-                        // todo: is there a nicer way of doing this type casting / object tx serialization
-                        //       in arrow?
-                        //
-                        // https://github.com/apache/arrow-rs/issues/1760
-
-                        let types = batch
-                            .column(0)
-                            .as_any()
-                            .downcast_ref::<StringArray>()
-                            .unwrap();
-                        let clients = batch
-                            .column(1)
-                            .as_any()
-                            .downcast_ref::<UInt16Array>()
-                            .unwrap();
-                        let ids = batch
-                            .column(2)
-                            .as_any()
-                            .downcast_ref::<UInt32Array>()
-                            .unwrap();
-                        let amounts = batch
-                            .column(3)
-                            .as_any()
-                            .downcast_ref::<StringArray>()
-                            .unwrap();
-                        for i in 0..batch.num_rows() {
-                            let intent = types.value(i);
-                            let client = clients.value(i);
-                            let id = ids.value(i);
-                            let amount = if amounts.is_null(i) {
-                                Decimal::ZERO
-                            } else {
-                                match Decimal::from_str(amounts.value(i).trim()) {
-                                    Ok(d) => d,
-                                    Err(_) => {
-                                        dbg!("skipping bad amount: {}", amounts.value(i));
-                                        continue;
-                                    }
-                                }
-                            };
-                            let tx = Tx { client, id, amount };
-                            let tx = match intent.trim() {
-                                "deposit" => Transaction::Deposit(tx),
-                                "withdraw" => Transaction::PendingWithdrawal(tx),
-                                "dispute" => Transaction::Dispute(tx),
-                                "resolve" => Transaction::Resolve(tx),
-                                "chargeback" => Transaction::Chargeback(tx),
-                                _ => {
-                                    dbg!("skipping unknown intent: {}", intent);
-                                    continue;
-                                }
-                            };
-                            let shard_idx = client as usize % senders.len();
-                            if let Err(e) = senders[shard_idx].send(vec![tx]) {
-                                dbg!("shard send failed: {}", e);
-                            }
-                        }
+        let assignment = Arc::new(Modulo::new(senders.len()));
+        Self {
+            rt,
+            senders,
+            assignment,
+            pool: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            batch_size: TX_CHUNK_SIZE,
+            order: InputOrder::Sequential,
+            follow: None,
+        }
+    }
+
+    /// keeps the tokio runtime and the parallel parsing pool on `cores` so the
+    /// reader does not compete with pinned shard workers
+    pub fn pinned(
+        senders: Vec<crossbeam::channel::Sender<TxBatch>>,
+        cores: Vec<usize>,
+    ) -> anyhow::Result<Self> {
+        if cores.is_empty() {
+            return Err(anyhow::anyhow!("the reader needs at least one core"));
+        }
+        topology::validate_cores(&cores)?;
+
+        let next = AtomicUsize::new(0);
+        let runtime_cores = cores.clone();
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(cores.len())
+            .thread_name("reader")
+            .enable_all()
+            .on_thread_start(move || {
+                let idx = next.fetch_add(1, Relaxed);
+                topology::pin_current(runtime_cores[idx % runtime_cores.len()]);
+            })
+            .build()?;
+
+        let pool_cores = cores.clone();
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(cores.len())
+            .thread_name(|idx| format!("parse-{}", idx))
+            .start_handler(move |idx| topology::pin_current(pool_cores[idx % pool_cores.len()]))
+            .build()?;
+
+        let assignment = Arc::new(Modulo::new(senders.len()));
+        Ok(Self {
+            rt,
+            senders,
+            assignment,
+            pool: Some(Arc::new(pool)),
+            stop: Arc::new(AtomicBool::new(false)),
+            batch_size: TX_CHUNK_SIZE,
+            order: InputOrder::Sequential,
+            follow: None,
+        })
+    }
+
+    pub fn with_assignment(mut self, assignment: Arc<dyn ShardAssignment>) -> Self {
+        self.assignment = assignment;
+        self
+    }
+
+    /// rows decoded and sent to the shards at a time
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// stops reading once `stop` is set, rows already sent still reach the shards
+    pub fn with_stop(mut self, stop: Arc<AtomicBool>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    /// how rows of several files are interleaved, sequential by default
+    pub fn with_input_order(mut self, order: InputOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// keeps reading the one input as it grows until stopped, checking for new
+    /// rows every `poll`.  starts at `from` when it belongs to the file
+    pub fn with_follow(mut self, poll: Duration, from: Option<Checkpoint>) -> Self {
+        self.follow = Some((poll, from));
+        self
+    }
+
+    /// reads every file to the end, or until stopped, and reports on each
+    pub fn consume(&self, tx_csvs: Vec<String>) -> anyhow::Result<Vec<FileStats>> {
+        if let Some((poll, from)) = &self.follow {
+            let [tx_csv] = tx_csvs.as_slice() else {
+                return Err(anyhow::anyhow!("only a single input file can be followed"));
+            };
+            let stats = follow::follow(
+                Path::new(tx_csv),
+                from.as_ref(),
+                &self.senders,
+                self.assignment.as_ref(),
+                self.batch_size,
+                *poll,
+                &self.stop,
+            )?;
+            return Ok(vec![stats]);
+        }
+        match self.order {
+            InputOrder::Sequential => self.rt.block_on(async {
+                let mut stats = vec![];
+                for tx_csv in tx_csvs {
+                    stats.push(self.spawn_file(tx_csv).await??);
+                }
+                Ok(stats)
+            }),
+            InputOrder::Concurrent => self.rt.block_on(async {
+                let handles: Vec<_> = tx_csvs
+                    .into_iter()
+                    .map(|tx_csv| self.spawn_file(tx_csv))
+                    .collect();
+                let mut stats = vec![];
+                for handle in handles {
+                    stats.push(handle.await??);
+                }
+                Ok(stats)
+            }),
+            InputOrder::Timestamp => {
+                let merge = || {
+                    consume_merged(
+                        &tx_csvs,
+                        &self.senders,
+                        self.assignment.as_ref(),
+                        self.batch_size,
+                        &self.stop,
+                    )
+                };
+                match &self.pool {
+                    Some(pool) => pool.install(merge),
+                    None => merge(),
+                }
+            }
+        }
+    }
+
+    fn spawn_file(&self, tx_csv: String) -> tokio::task::JoinHandle<anyhow::Result<FileStats>> {
+        let senders = self.senders.clone();
+        let assignment = self.assignment.clone();
+        let pool = self.pool.clone();
+        let stop = self.stop.clone();
+        let batch_size = self.batch_size;
+        self.rt.spawn(async move {
+            let started = Instant::now();
+            let path = Path::new(&tx_csv);
+            let bytes = tokio::fs::metadata(path).await?.len();
+            let splittable = Compression::detect(path)? == Compression::None
+                && bytes >= PARALLEL_PARSE_MIN_BYTES;
+            if splittable {
+                let path = path.to_path_buf();
+                let stats = tokio::task::spawn_blocking(move || {
+                    let parse = || {
+                        consume_parallel(&path, &senders, assignment.as_ref(), batch_size, &stop)
+                    };
+                    match pool {
+                        Some(pool) => pool.install(parse),
+                        None => parse(),
                     }
-                    Ok::<(), anyhow::Error>(())
+                })
+                .await??;
+                return Ok(FileStats {
+                    elapsed: started.elapsed(),
+                    ..stats
                 });
-                handles.push(handle);
             }
-            for handle in handles {
-                handle.await??;
+
+            let mut stats = FileStats::new(&tx_csv, bytes);
+            let schema = input_schema(&read_header(path)?);
+            let reader = open_async_decompressed(path).await?;
+            let decoder = ReaderBuilder::new(schema)
+                .with_header(true)
+                .with_batch_size(batch_size)
+                .build_decoder();
+
+            let mut stream = decode_stream(decoder, reader);
+
+            while let Some(batch) = stream.try_next().await? {
+                if stop.load(Relaxed) {
+                    info!(file = %tx_csv, "stop requested, intake stopped");
+                    break;
+                }
+                let sharded = shard_batch(&tx_csv, &batch, senders.len(), assignment.as_ref());
+                stats.rows += batch.num_rows() as u64;
+                stats.transactions += sent(&sharded);
+                send_sharded(&senders, sharded);
             }
-            Ok(())
+            stats.elapsed = started.elapsed();
+            Ok(stats)
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    const ROWS: &[u8] = b"type,client,tx,amount\ndeposit,1,1,1.0\n";
+
+    fn file(suffix: &str, content: &[u8]) -> NamedTempFile {
+        let mut file = tempfile::Builder::new().suffix(suffix).tempfile().unwrap();
+        file.write_all(content).unwrap();
+        file
+    }
+
+    fn gzip(content: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn compression_is_told_from_the_extension_first() {
+        for (suffix, expected) in [
+            (".csv.gz", Compression::Gzip),
+            (".gzip", Compression::Gzip),
+            (".zst", Compression::Zstd),
+            (".zstd", Compression::Zstd),
+        ] {
+            assert_eq!(
+                Compression::detect(file(suffix, ROWS).path()).unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn renamed_drops_are_told_from_their_magic() {
+        let gz = file(".csv", &gzip(ROWS));
+        assert_eq!(Compression::detect(gz.path()).unwrap(), Compression::Gzip);
+        let zst = file(".csv", &zstd::encode_all(ROWS, 0).unwrap());
+        assert_eq!(Compression::detect(zst.path()).unwrap(), Compression::Zstd);
+        for content in [ROWS, b"", &[0x1f]] {
+            let plain = file(".csv", content);
+            assert_eq!(
+                Compression::detect(plain.path()).unwrap(),
+                Compression::None
+            );
+        }
+
+        let mut rows = vec![];
+        open_decompressed(gz.path())
+            .unwrap()
+            .read_to_end(&mut rows)
+            .unwrap();
+        assert_eq!(rows, ROWS);
+    }
+
+    #[test]
+    fn ranges_cover_the_body_and_end_on_newlines() {
+        let body = b"deposit,1,1,1.0\ndeposit,2,2,2.0\ndeposit,3,3,3.0";
+        for chunk_size in [0, 1, 5, 16, 17, 100] {
+            let ranges = newline_aligned_ranges(body, chunk_size);
+            assert_eq!(ranges.first().unwrap().start, 0);
+            assert_eq!(ranges.last().unwrap().end, body.len());
+            for pair in ranges.windows(2) {
+                assert_eq!(pair[0].end, pair[1].start);
+                assert_eq!(body[pair[0].end - 1], b'\n');
+            }
+        }
+        assert_eq!(newline_aligned_ranges(body, 100), vec![0..body.len()]);
+        assert!(newline_aligned_ranges(b"", 16).is_empty());
+    }
+
+    #[test]
+    fn chunks_parse_into_shards_across_batches() {
+        let chunk = b"deposit,1,1,1.0\nwithdraw,2,2,0.5\ndispute,1,1,\ndeposit,3,3,2.5\n";
+        let schema = input_schema("type,client,tx,amount");
+        let assignment = crate::assignment::Modulo::new(2);
+        let (sharded, rows) = parse_chunk("test", chunk, 2, &assignment, schema, 1).unwrap();
+        assert_eq!(rows, 4);
+        let clients: Vec<Vec<ClientId>> = sharded
+            .iter()
+            .map(|txs| {
+                txs.iter()
+                    .map(|transaction| transaction.tx().client)
+                    .collect()
+            })
+            .collect();
+        assert_eq!(clients, vec![vec![2], vec![1, 1, 3]]);
+        assert!(matches!(sharded[1][1], Transaction::Dispute(_)));
+    }
+}
@@ -0,0 +1,245 @@
+use crate::output::format_amount;
+use crate::shard::{Merge, ShardResults};
+use crate::transaction::{ClientId, TxId};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+/// the accounts a client's book posts between
+///
+/// `Available` and `Held` are the client's sub-ledgers, the others are
+/// system accounts shared by every client and only summed up at the end
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LedgerAccount {
+    Available,
+    Held,
+    /// money on its way in from or out to the payment partner
+    PartnerSettlement,
+    /// money returned to the partner on a chargeback
+    ChargebackLosses,
+    /// fees charged to clients
+    FeeRevenue,
+}
+
+impl LedgerAccount {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LedgerAccount::Available => "available",
+            LedgerAccount::Held => "held",
+            LedgerAccount::PartnerSettlement => "partner_settlement",
+            LedgerAccount::ChargebackLosses => "chargeback_losses",
+            LedgerAccount::FeeRevenue => "fee_revenue",
+        }
+    }
+
+    fn idx(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// why a posting was made
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Posting {
+    Deposit,
+    DepositFee,
+    Withdraw,
+    WithdrawFee,
+    Dispute,
+    Resolve,
+    Chargeback,
+    DepositFeeRefund,
+    ChargebackFee,
+}
+
+impl Posting {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Posting::Deposit => "deposit",
+            Posting::DepositFee => "deposit_fee",
+            Posting::Withdraw => "withdraw",
+            Posting::WithdrawFee => "withdraw_fee",
+            Posting::Dispute => "dispute",
+            Posting::Resolve => "resolve",
+            Posting::Chargeback => "chargeback",
+            Posting::DepositFeeRefund => "deposit_fee_refund",
+            Posting::ChargebackFee => "chargeback_fee",
+        }
+    }
+
+    /// how the posting moves the client's total funds, worked out from why it
+    /// was made rather than from the accounts it was posted between
+    fn funds(&self, amount: Decimal) -> Decimal {
+        match self {
+            Posting::Deposit | Posting::DepositFeeRefund => amount,
+            Posting::Dispute | Posting::Resolve => Decimal::ZERO,
+            Posting::DepositFee
+            | Posting::Withdraw
+            | Posting::WithdrawFee
+            | Posting::Chargeback
+            | Posting::ChargebackFee => -amount,
+        }
+    }
+}
+
+/// a balanced posting, `amount` leaves `debit` and arrives in `credit`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub tx: TxId,
+    pub kind: Posting,
+    pub debit: LedgerAccount,
+    pub credit: LedgerAccount,
+    pub amount: Decimal,
+}
+
+/// a client's journal and the balances derived from it
+///
+/// balances are credits minus debits, so the client sub-ledgers are positive
+/// and the system accounts carry the other side.  the client's total is kept
+/// apart from the sub-ledgers, from the kind of each posting, so a posting
+/// made between the wrong accounts shows up as `available + held != total`
+#[derive(Serialize, Deserialize)]
+pub struct DoubleEntryBook {
+    balances: [Decimal; 5],
+    total: Decimal,
+    // note: only kept when a journal file was asked for
+    entries: Option<Vec<Entry>>,
+}
+
+impl DoubleEntryBook {
+    pub fn new() -> Self {
+        DoubleEntryBook {
+            balances: [Decimal::ZERO; 5],
+            total: Decimal::ZERO,
+            entries: None,
+        }
+    }
+
+    pub fn keep_entries(&mut self) {
+        self.entries.get_or_insert_with(Vec::new);
+    }
+
+    pub fn take_entries(&mut self) -> Option<Vec<Entry>> {
+        self.entries.take()
+    }
+
+    pub fn post(&mut self, entry: Entry) {
+        self.balances[entry.debit.idx()] -= entry.amount;
+        self.balances[entry.credit.idx()] += entry.amount;
+        self.total += entry.kind.funds(entry.amount);
+        if let Some(entries) = self.entries.as_mut() {
+            entries.push(entry);
+        }
+    }
+
+    pub fn balance(&self, account: LedgerAccount) -> Decimal {
+        self.balances[account.idx()]
+    }
+
+    pub fn available_funds(&self) -> Decimal {
+        self.balance(LedgerAccount::Available)
+    }
+
+    pub fn held_funds(&self) -> Decimal {
+        self.balance(LedgerAccount::Held)
+    }
+
+    pub fn total_funds(&self) -> Decimal {
+        self.total
+    }
+}
+
+impl Default for DoubleEntryBook {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// one client's journal, entries in posting order
+pub struct ClientJournal {
+    pub client: ClientId,
+    pub entries: Vec<Entry>,
+}
+
+/// journals of every shard, ordered by client id
+pub type JournalLog = ShardResults<Vec<ClientJournal>>;
+
+impl Merge for Vec<ClientJournal> {
+    fn merge(&mut self, other: Self) {
+        self.extend(other);
+        self.sort_unstable_by_key(|journal| journal.client);
+    }
+}
+
+#[derive(Serialize)]
+struct JournalRow {
+    client: ClientId,
+    seq: usize,
+    tx: TxId,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    debit: &'static str,
+    credit: &'static str,
+    amount: String,
+}
+
+pub fn write_journal<W: Write>(journals: &[ClientJournal], out: W) -> anyhow::Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    for journal in journals {
+        for (seq, entry) in journal.entries.iter().enumerate() {
+            writer.serialize(JournalRow {
+                client: journal.client,
+                seq: seq + 1,
+                tx: entry.tx,
+                kind: entry.kind.as_str(),
+                debit: entry.debit.as_str(),
+                credit: entry.credit.as_str(),
+                amount: format_amount(entry.amount),
+            })?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(kind: Posting, debit: LedgerAccount, credit: LedgerAccount, amount: i64) -> Entry {
+        Entry {
+            tx: 1,
+            kind,
+            debit,
+            credit,
+            amount: Decimal::from(amount),
+        }
+    }
+
+    #[test]
+    fn misrouted_posting_breaks_the_total() {
+        let mut book = DoubleEntryBook::new();
+        book.post(entry(
+            Posting::Deposit,
+            LedgerAccount::PartnerSettlement,
+            LedgerAccount::Available,
+            10,
+        ));
+        assert_eq!(
+            book.available_funds() + book.held_funds(),
+            book.total_funds()
+        );
+
+        // note: a dispute must move money to held, not out of the client's funds
+        book.post(entry(
+            Posting::Dispute,
+            LedgerAccount::Available,
+            LedgerAccount::ChargebackLosses,
+            4,
+        ));
+        assert_eq!(book.total_funds(), Decimal::from(10));
+        assert_ne!(
+            book.available_funds() + book.held_funds(),
+            book.total_funds()
+        );
+    }
+}
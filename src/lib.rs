@@ -1,5 +1,23 @@
-mod account;
-mod io;
-mod output;
-mod shard;
-mod transaction;
+pub mod account;
+pub mod assignment;
+pub mod config;
+pub mod diff;
+pub mod engine;
+pub mod fees;
+pub mod follow;
+pub mod io;
+pub mod ledger;
+pub mod limits;
+pub mod logging;
+pub mod metrics;
+pub mod output;
+pub mod reconcile;
+pub mod reference;
+pub mod replay;
+pub mod retention;
+pub mod shard;
+pub mod shutdown;
+pub mod statement;
+pub mod store;
+pub mod topology;
+pub mod transaction;
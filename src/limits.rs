@@ -0,0 +1,47 @@
+use crate::transaction::ClientId;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+/// approved credit lines, a client may withdraw down to `-limit` available
+#[derive(Debug, Default)]
+pub struct CreditLimits {
+    limits: HashMap<ClientId, Decimal>,
+}
+
+#[derive(Deserialize)]
+struct LimitRow {
+    client: ClientId,
+    limit: String,
+}
+
+impl CreditLimits {
+    /// loads a `client,limit` csv, clients missing from the file have no credit
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let mut limits = HashMap::new();
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)?;
+        for row in reader.deserialize() {
+            let row: LimitRow = row?;
+            let limit = Decimal::from_str(&row.limit).map_err(|e| {
+                anyhow::anyhow!("bad limit {:?} for client {}: {}", row.limit, row.client, e)
+            })?;
+            if limit.is_sign_negative() {
+                return Err(anyhow::anyhow!(
+                    "client {} has a negative credit limit {}",
+                    row.client,
+                    limit
+                ));
+            }
+            limits.insert(row.client, limit.round_dp(4));
+        }
+        Ok(CreditLimits { limits })
+    }
+
+    pub fn get(&self, client: ClientId) -> Decimal {
+        self.limits.get(&client).copied().unwrap_or(Decimal::ZERO)
+    }
+}
@@ -1,34 +1,360 @@
 use std::env;
+use std::process::ExitCode;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering::SeqCst;
-use std::thread::sleep;
-
-mod account;
-mod engine;
-mod io;
-mod output;
-mod shard;
-mod transaction;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use arrow::datatypes::{DataType, Field, Schema};
 use arrow_csv::reader::Format;
-use engine::Engine;
-use io::ConcurrentAsyncFileDescriptorReader;
-use output::write_output_accounts;
+use clap::{Args, Parser, Subcommand};
+use kraken::assignment::{self, Strategy};
+use kraken::config::Config;
+use kraken::diff::{self, read_accounts};
+use kraken::engine::{Engine, EngineOptions};
+use kraken::fees::Fees;
+use kraken::follow::Checkpoint;
+use kraken::io::{
+    ConcurrentAsyncFileDescriptorReader, InputOrder, expand_inputs, open_decompressed,
+};
+use kraken::ledger::write_journal;
+use kraken::limits::CreditLimits;
+use kraken::logging::{self, LogFormat};
+use kraken::metrics;
+use kraken::output::{
+    AccountWriter, OutputFormat, OutputOrder, format_amount, write_engine_output,
+};
+use kraken::replay::{Cutoff, Replay};
+use kraken::retention::Horizon;
+use kraken::shard::IdleStrategy;
+use kraken::shutdown::Shutdown;
+use kraken::statement::{Clients, StatementFormat, write_statements};
+use kraken::store::{self, FileStoreConfig};
+use kraken::topology::CoreList;
+use kraken::transaction::{ClientId, TxId};
 use std::fs::File;
-use std::path::Path;
+use std::io::{BufWriter, Write, stdout};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+#[derive(Parser)]
+#[command(
+    name = "kraken",
+    about = "payments engine",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// transactions csvs or globs such as `drops/2026-10-*.csv`, optionally
+    /// gzip or zstd compressed
+    #[arg(required_unless_present = "print_config")]
+    inputs: Vec<String>,
+
+    /// toml file with engine settings, keyed like the flags below i.e.
+    /// `dispute_window_ms = 5`.  flags given on the command line win
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// print the effective configuration as toml and exit
+    #[arg(long)]
+    print_config: bool,
+
+    /// write the prometheus metrics to this file once the run completes
+    #[arg(long)]
+    metrics_file: Option<PathBuf>,
+
+    /// serve the prometheus metrics over http on this address while running
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// how rows of several inputs are interleaved, `sequential` by default
+    #[arg(long, value_enum)]
+    input_order: Option<InputOrder>,
+
+    /// keep reading the input as it grows, like `tail -f`, until SIGINT or
+    /// SIGTERM.  takes a single uncompressed file
+    #[arg(long)]
+    follow: bool,
+
+    /// how often a followed file is checked for new rows, `100` by default
+    #[arg(long)]
+    follow_poll_ms: Option<u64>,
+
+    /// json file holding the byte offset a followed file was consumed up to,
+    /// read on start and written on stop.  pair it with --store to carry the
+    /// accounts over as well
+    #[arg(long, requires = "follow")]
+    checkpoint: Option<PathBuf>,
+
+    /// rows decoded and sent to the shards at a time, `5000` by default
+    #[arg(long)]
+    batch_size: Option<usize>,
+
+    /// how long a withdrawal waits for a dispute to overtake it, zero applies
+    /// every transaction in input order.  `0` by default, anything else makes
+    /// the balances depend on timing
+    #[arg(long)]
+    dispute_window_ms: Option<u64>,
+
+    /// forget deposits and withdrawals after this many of the client's
+    /// transactions i.e. `100000`.  kept forever by default
+    #[arg(long)]
+    dispute_horizon: Option<Horizon>,
+
+    /// withdrawals each worker holds back for their dispute window at once,
+    /// further ones hold up the intake until the oldest is due.  `256` by default
+    #[arg(long)]
+    pending_queue: Option<usize>,
+
+    /// batches each worker's channel holds before the reader waits,
+    /// unbounded by default
+    #[arg(long)]
+    channel_capacity: Option<usize>,
+
+    /// number of shard workers, defaults to one per worker core
+    #[arg(long)]
+    workers: Option<usize>,
+
+    /// cores the shard workers are pinned to i.e. `2-7`, defaults to every
+    /// core not given to the reader
+    #[arg(long)]
+    worker_cores: Option<CoreList>,
+
+    /// cores the reader's runtime and parsing pool are pinned to i.e. `0,1`
+    #[arg(long)]
+    reader_cores: Option<CoreList>,
+
+    /// let the os schedule the shard workers instead of pinning them
+    #[arg(long)]
+    no_pin: bool,
+
+    /// what idle workers do, `park` frees the core at the cost of wake up
+    /// latency.  `spin` by default
+    #[arg(long, value_enum)]
+    idle: Option<IdleStrategy>,
+
+    /// where the accounts are written, stdout when not given
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+
+    /// `csv` by default
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// write a per-client statement with the balances after every transaction
+    #[arg(long)]
+    statements: Option<PathBuf>,
+
+    /// how the statements are written
+    #[arg(long, value_enum, default_value = "csv", requires = "statements")]
+    statement_format: StatementFormat,
+
+    /// clients that get a statement, `all` or a list such as `1,7,100-200`
+    #[arg(long, default_value = "all", requires = "statements")]
+    statement_clients: Clients,
+
+    /// `client,limit` csv of approved credit lines, withdrawals may take
+    /// available down to `-limit`
+    #[arg(long)]
+    credit_limits: Option<PathBuf>,
+
+    /// `tier,type,flat,bps` csv of fees on deposits, withdrawals and chargebacks
+    #[arg(long)]
+    fees: Option<PathBuf>,
+
+    /// `client,tier` csv, clients not in it pay the `default` tier.  needs fees
+    #[arg(long)]
+    fee_tiers: Option<PathBuf>,
+
+    /// add the `credit_limit` and `credit_used` columns to the output, on by
+    /// default with --credit-limits
+    #[arg(long)]
+    credit_columns: bool,
+
+    /// write every balanced journal entry as csv, debit and credit per posting
+    #[arg(long)]
+    journal: Option<PathBuf>,
+
+    /// write the reconciliation summary of the run as json to this file
+    #[arg(long)]
+    reconciliation: Option<PathBuf>,
+
+    /// keep the accounts in this file instead of memory, accounts found in it
+    /// carry on from the run that wrote them
+    #[arg(long)]
+    store: Option<PathBuf>,
+
+    /// accounts each worker keeps in memory with a store, `100000` by default
+    #[arg(long)]
+    store_cache: Option<usize>,
+
+    /// row order of the account output, `client` by default
+    #[arg(long, value_enum)]
+    order: Option<OutputOrder>,
+
+    /// how clients are spread over the shards, `modulo` by default
+    #[arg(long, value_enum)]
+    assignment: Option<Strategy>,
+
+    /// `client,shard` csv used by the static assignment
+    #[arg(long)]
+    assignment_map: Option<PathBuf>,
+
+    /// log filter in the `RUST_LOG` syntax i.e. `debug` or `kraken::shard=debug`
+    #[arg(long, default_value = "info", global = true)]
+    log_level: String,
+
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, global = true)]
+    log_format: LogFormat,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// balances as they stood just before a transaction or at a point in time
+    Replay(ReplayArgs),
+    /// compare two account outputs, exits 0 when they match, 1 when they
+    /// differ and 2 when either can not be read
+    Diff(DiffArgs),
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    /// the earlier output, csv, jsonl or arrow ipc
+    left: PathBuf,
+
+    /// the later output, compared against the left one
+    right: PathBuf,
+
+    /// print the report as json instead of text
+    #[arg(long)]
+    json: bool,
+}
+
+// note: the exit codes of diff(1)
+const DIFF_SAME: u8 = 0;
+const DIFF_CHANGED: u8 = 1;
+const DIFF_TROUBLE: u8 = 2;
+
+#[derive(Args)]
+struct ReplayArgs {
+    /// transactions csvs or globs, replayed one after another
+    #[arg(required = true)]
+    inputs: Vec<String>,
+
+    /// stop just before the first row with this tx id
+    #[arg(long, required_unless_present = "at", conflicts_with = "at")]
+    before_tx: Option<TxId>,
+
+    /// apply the rows stamped at or before this time in the `timestamp` column
+    #[arg(long)]
+    at: Option<u64>,
+
+    /// replay and output only this client
+    #[arg(long)]
+    client: Option<ClientId>,
+
+    /// how rows of several inputs are interleaved, as for a run.  a time
+    /// cut-off always merges them on `timestamp`
+    #[arg(long, value_enum)]
+    input_order: Option<InputOrder>,
+
+    /// account store a run left behind to start from, the inputs being what
+    /// came after that run.  the store is not changed
+    #[arg(long)]
+    snapshot: Option<PathBuf>,
+
+    /// toml file the credit limits, fees, fee tiers, dispute horizon and output
+    /// format are taken from, as for a run
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    #[arg(long)]
+    credit_limits: Option<PathBuf>,
+
+    #[arg(long)]
+    fees: Option<PathBuf>,
+
+    #[arg(long)]
+    fee_tiers: Option<PathBuf>,
+
+    #[arg(long)]
+    dispute_horizon: Option<Horizon>,
+
+    /// where the accounts are written, stdout when not given
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    #[arg(long)]
+    credit_columns: bool,
+}
+
+/// the config file, or the defaults, with the flags given on the command line on top
+fn configure(cli: &Cli) -> anyhow::Result<Config> {
+    let mut config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    macro_rules! overrides {
+        ($($field:ident),* $(,)?) => {
+            $(
+                if let Some(value) = cli.$field.clone() {
+                    config.$field = value.into();
+                }
+            )*
+        };
+    }
+    overrides!(
+        input_order,
+        follow_poll_ms,
+        batch_size,
+        workers,
+        worker_cores,
+        reader_cores,
+        idle,
+        dispute_window_ms,
+        dispute_horizon,
+        pending_queue,
+        channel_capacity,
+        assignment,
+        assignment_map,
+        format,
+        order,
+        credit_limits,
+        fees,
+        fee_tiers,
+        store,
+        store_cache,
+    );
+    if cli.follow {
+        config.follow = true;
+    }
+    if cli.no_pin {
+        config.pin = false;
+    }
+    if cli.credit_columns {
+        config.credit_columns = true;
+    }
+    config.resolve_layout()?;
+    config.validate()?;
+    Ok(config)
+}
 
 fn is_csv(path: &str) -> anyhow::Result<()> {
-    let mut file = File::open(path)?;
+    let mut file = open_decompressed(Path::new(path))?;
     let format = Format::default().with_header(true);
     let (schema, _) = format.infer_schema(&mut file, Some(10))?;
 
     let expected = Schema::new(vec![
         Field::new("type", DataType::Utf8, false),
-        Field::new("client", DataType::UInt16, false),
-        Field::new("tx", DataType::UInt32, false),
+        Field::new("client", DataType::UInt64, false),
+        Field::new("tx", DataType::UInt64, false),
         Field::new("amount", DataType::Decimal128(10, 4), true),
     ]);
 
@@ -58,36 +384,300 @@ fn resolve_csv_path(path: &str) -> anyhow::Result<String> {
     }
 }
 
-fn main() -> anyhow::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let txs_file = args[1].clone();
-    is_csv(txs_file.as_str())?;
-    resolve_csv_path(txs_file.as_str())?;
+/// runs the inputs through the accounts up to the cut-off and writes the
+/// balances as they stood then
+fn replay(args: ReplayArgs) -> anyhow::Result<ExitCode> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    config.credit_limits = args.credit_limits.or(config.credit_limits);
+    config.fees = args.fees.or(config.fees);
+    config.fee_tiers = args.fee_tiers.or(config.fee_tiers);
+    config.dispute_horizon = args.dispute_horizon.or(config.dispute_horizon);
+    config.format = args.format.unwrap_or(config.format);
+    config.input_order = args.input_order.unwrap_or(config.input_order);
+    config.validate()?;
+
+    let inputs = expand_inputs(&args.inputs)?;
+    for input in &inputs {
+        is_csv(input)?;
+    }
+    let cutoff = match (args.before_tx, args.at) {
+        (Some(tx), _) => Cutoff::BeforeTx(tx),
+        (None, Some(at)) => Cutoff::At(at),
+        (None, None) => unreachable!("clap requires a cut-off"),
+    };
+    let mut replay = Replay::new(cutoff).with_input_order(config.input_order);
+    if let Some(client) = args.client {
+        replay = replay.with_client(client);
+    }
+    if let Some(path) = &config.credit_limits {
+        replay = replay.with_credit_limits(Arc::new(CreditLimits::load(path)?));
+    }
+    if let Some(path) = &config.fees {
+        replay = replay.with_fees(Arc::new(Fees::load(path, config.fee_tiers.as_deref())?));
+    }
+    if let Some(horizon) = config.dispute_horizon {
+        replay = replay.with_dispute_horizon(horizon);
+    }
+    if let Some(path) = &args.snapshot {
+        let accounts = store::snapshot(path, args.client)?;
+        info!(accounts = accounts.len(), snapshot = %path.display(), "starting from the snapshot");
+        replay = replay.with_snapshot(accounts);
+    }
+    info!(files = ?inputs, cutoff = ?cutoff, client = ?args.client, "replaying");
+
+    let (accounts, summary) = replay.run(&inputs)?;
+    info!(
+        applied = summary.applied,
+        rejected = summary.rejected,
+        skipped = summary.skipped,
+        accounts = accounts.len(),
+        "replayed"
+    );
+    if let Cutoff::BeforeTx(tx) = cutoff
+        && !summary.reached
+    {
+        warn!(tx, "tx not in the input, the balances are as of its end");
+    }
+
+    let out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(stdout()),
+    };
+    let mut writer = AccountWriter::with_format(out, config.format)
+        .with_credit_columns(args.credit_columns || config.credit_limits.is_some());
+    writer.write(&accounts)?;
+    writer.finish()?;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// reports the clients added, removed and changed from the left output to the right
+fn diff(args: DiffArgs) -> anyhow::Result<ExitCode> {
+    let left = read_accounts(&args.left)?;
+    let right = read_accounts(&args.right)?;
+    let report = diff::diff(&left, &right);
+    let mut out = BufWriter::new(stdout());
+    match args.json {
+        true => {
+            serde_json::to_writer_pretty(&mut out, &report)?;
+            writeln!(out)?;
+        }
+        false => report.write_text(&mut out)?,
+    }
+    out.flush()?;
+    match report.is_empty() {
+        true => Ok(ExitCode::from(DIFF_SAME)),
+        false => Ok(ExitCode::from(DIFF_CHANGED)),
+    }
+}
+
+fn main() -> anyhow::Result<ExitCode> {
+    let cli = Cli::parse();
+    if let Some(command) = cli.command {
+        logging::init(&cli.log_level, cli.log_format)?;
+        return match command {
+            Command::Replay(args) => replay(args),
+            // note: a plain error would exit 1, which here means the files differ
+            Command::Diff(args) => diff(args).or_else(|e| {
+                eprintln!("Error: {e:?}");
+                Ok(ExitCode::from(DIFF_TROUBLE))
+            }),
+        };
+    }
+    let config = configure(&cli)?;
+    if cli.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(ExitCode::SUCCESS);
+    }
+    logging::init(&cli.log_level, cli.log_format)?;
+    info!("effective configuration\n{}", config.to_toml()?.trim_end());
+    let shutdown = Shutdown::install()?;
+
+    let inputs = expand_inputs(&cli.inputs)?;
+    for input in &inputs {
+        is_csv(input)?;
+        resolve_csv_path(input)?;
+    }
+    info!(files = ?inputs, order = ?config.input_order, "consuming files");
+    let checkpoint = match &cli.checkpoint {
+        Some(path) => Checkpoint::load(path)?,
+        None => None,
+    };
+    if cli.checkpoint.is_some() && config.store.is_none() {
+        warn!("a checkpoint without a store resumes the rows but not the balances");
+    }
 
-    dbg!("consuming file {:?}", &txs_file);
+    if let Some(addr) = cli.metrics_addr {
+        metrics::serve(addr)?;
+    }
 
-    let done = Arc::new(AtomicBool::new(false));
+    // note: the layout is resolved by `configure`
+    let num_workers = config.workers.unwrap_or(1);
+    let worker_cores = config
+        .worker_cores
+        .clone()
+        .map_or(vec![], |CoreList(cores)| cores);
 
-    let num_workers = std::thread::available_parallelism()
-        .map(|n| n.get())
-        .map_err(|e| anyhow!("failed to get available cores {:?}", e))?;
+    // note: the store needs the assignment to tell which stored accounts are whose
+    let assignment = assignment::build(
+        config.assignment,
+        num_workers,
+        config.assignment_map.as_deref(),
+        &inputs,
+    )?;
 
-    let (engine, tx_senders) = Engine::new(num_workers, done.clone())?;
+    let options = EngineOptions {
+        workers: num_workers,
+        pin: config.pin,
+        cores: worker_cores,
+        dispute_window_ms: config.dispute_window_ms,
+        idle: config.idle,
+        pending_queue: config.pending_queue,
+        channel_capacity: config.channel_capacity,
+        statements: cli
+            .statements
+            .as_ref()
+            .map(|_| Arc::new(cli.statement_clients.clone())),
+        journal: cli.journal.is_some(),
+        credit_limits: match &config.credit_limits {
+            Some(path) => Some(Arc::new(CreditLimits::load(path)?)),
+            None => None,
+        },
+        dispute_horizon: config.dispute_horizon,
+        fees: match &config.fees {
+            Some(path) => Some(Arc::new(Fees::load(path, config.fee_tiers.as_deref())?)),
+            None => None,
+        },
+        store: match &config.store {
+            Some(path) => Some(FileStoreConfig {
+                db: store::open(path)?,
+                cache_accounts: config.store_cache,
+                assignment: assignment.clone(),
+            }),
+            None => None,
+        },
+    };
+    info!(
+        workers = options.workers,
+        pin = options.pin,
+        cores = ?options.cores,
+        reader_cores = ?config.reader_cores.as_ref().map(|CoreList(cores)| cores),
+        "engine layout"
+    );
+    // note: the shards stop once the reader closes the channels, never halfway
+    let (engine, tx_senders) = Engine::new(options, Arc::new(AtomicBool::new(false)))?;
+    // note: the output file is created up front so a bad path fails before any work
+    let out: Box<dyn Write + Send> = match &cli.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(stdout()),
+    };
+    let statements_out = match &cli.statements {
+        Some(path) => Some(BufWriter::new(File::create(path)?)),
+        None => None,
+    };
+    let journal_out = match &cli.journal {
+        Some(path) => Some(BufWriter::new(File::create(path)?)),
+        None => None,
+    };
+    let statements = engine.statements();
+    let journal = engine.journal();
+    let reconciliation = engine.reconciliation();
+    let writer = AccountWriter::with_format(out, config.format)
+        .with_credit_columns(config.credit_columns || config.credit_limits.is_some());
+    let order = config.order;
     let handler = std::thread::spawn(move || -> anyhow::Result<()> {
-        let oas = engine.run()?;
-        write_output_accounts(oas)?;
-        Ok(())
+        write_engine_output(engine, order, writer)
     });
 
-    ConcurrentAsyncFileDescriptorReader::new(tx_senders).consume(vec![txs_file])?;
+    let reader = match config.reader_cores.clone() {
+        Some(CoreList(cores)) => ConcurrentAsyncFileDescriptorReader::pinned(tx_senders, cores)?,
+        None => ConcurrentAsyncFileDescriptorReader::new(tx_senders),
+    };
+    let reader = match config.follow {
+        true => reader.with_follow(Duration::from_millis(config.follow_poll_ms), checkpoint),
+        false => reader,
+    };
+    // note: the reader is dropped with this statement, which closes the channels
+    let files = reader
+        .with_assignment(assignment)
+        .with_batch_size(config.batch_size)
+        .with_input_order(config.input_order)
+        .with_stop(shutdown.requested())
+        .consume(inputs)?;
+    for file in &files {
+        info!(
+            file = %file.path,
+            bytes = file.bytes,
+            rows = file.rows,
+            transactions = file.transactions,
+            skipped = file.skipped(),
+            out_of_order = file.out_of_order,
+            millis = file.elapsed.as_millis() as u64,
+            "file consumed"
+        );
+        if file.out_of_order > 0 {
+            warn!(
+                file = %file.path,
+                rows = file.out_of_order,
+                "rows go back in time, they were merged where they stood"
+            );
+        }
+    }
 
-    dbg!(
-        "sleep for X milliseconds\n
-          the larger the batch size the larger our sleep needs to be",
-    );
+    match shutdown.signal() {
+        Some(signal) => info!(signal, "shutdown requested, draining the shards"),
+        None => debug!("reader finished, waiting for the shards to drain"),
+    }
+    handler
+        .join()
+        .map_err(|_| anyhow!("engine thread panicked"))??;
 
-    sleep(std::time::Duration::from_millis(1000));
-    done.store(true, SeqCst);
-    handler.join();
-    Ok(())
+    // note: written once the shards drained and the store was flushed, so the
+    // offset never runs ahead of the balances
+    if let (Some(path), Some(resume)) = (
+        &cli.checkpoint,
+        files.first().and_then(|f| f.resume.as_ref()),
+    ) {
+        resume.save(path)?;
+        info!(offset = resume.offset, checkpoint = %path.display(), "checkpoint written");
+    }
+
+    if let Some(out) = statements_out {
+        write_statements(&statements.take(), cli.statement_format, out)?;
+    }
+    if let Some(out) = journal_out {
+        write_journal(&journal.take(), out)?;
+    }
+
+    if let Some(path) = cli.metrics_file {
+        metrics::dump(&path)?;
+    }
+
+    let totals = reconciliation.take();
+    info!(
+        accounts = totals.accounts,
+        applied = totals.applied,
+        rejected = totals.rejected,
+        deposited = %format_amount(totals.deposited),
+        withdrawn = %format_amount(totals.withdrawn),
+        charged_back = %format_amount(totals.charged_back),
+        held = %format_amount(totals.held),
+        total = %format_amount(totals.total),
+        pending = totals.pending,
+        "reconciliation"
+    );
+    if let Some(path) = &cli.reconciliation {
+        totals.write(BufWriter::new(File::create(path)?))?;
+    }
+    totals.ensure_balanced()?;
+    if let Some(code) = shutdown.exit_code() {
+        warn!(
+            code,
+            "stopped by a signal, the output covers the rows read so far"
+        );
+        return Ok(ExitCode::from(code));
+    }
+    Ok(ExitCode::SUCCESS)
 }
@@ -0,0 +1,139 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts,
+    TextEncoder, exponential_buckets, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge_vec,
+};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+// longest a scrape may take to send its request or read the response
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    pub static ref ROWS_PARSED: IntCounter =
+        register_int_counter!("kraken_rows_parsed_total", "csv rows decoded by the reader")
+            .unwrap();
+    pub static ref ROWS_REJECTED: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "kraken_rows_rejected_total",
+            "rows dropped by the reader or refused by an account, by reason"
+        ),
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref TRANSACTIONS_EVICTED: IntCounter = register_int_counter!(
+        "kraken_transactions_evicted_total",
+        "deposits and withdrawals dropped from memory past the dispute horizon"
+    )
+    .unwrap();
+    pub static ref TRANSACTIONS_APPLIED: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "kraken_transactions_applied_total",
+            "transactions applied to an account"
+        ),
+        &["shard"]
+    )
+    .unwrap();
+    pub static ref CHANNEL_DEPTH: IntGaugeVec = register_int_gauge_vec!(
+        Opts::new(
+            "kraken_channel_depth",
+            "batches queued on the shard's channel"
+        ),
+        &["shard"]
+    )
+    .unwrap();
+    pub static ref PENDING_WITHDRAWALS: IntGaugeVec = register_int_gauge_vec!(
+        Opts::new(
+            "kraken_pending_withdrawals",
+            "withdrawals waiting out the dispute window"
+        ),
+        &["shard"]
+    )
+    .unwrap();
+    pub static ref LOCKED_ACCOUNTS: IntGaugeVec = register_int_gauge_vec!(
+        Opts::new("kraken_locked_accounts", "accounts locked by a chargeback"),
+        &["shard"]
+    )
+    .unwrap();
+    pub static ref INGEST_TO_APPLY_SECONDS: HistogramVec = register_histogram_vec!(
+        HistogramOpts::new(
+            "kraken_ingest_to_apply_seconds",
+            "time from a batch being decoded to it being applied by its shard"
+        )
+        .buckets(exponential_buckets(0.0001, 4.0, 10).unwrap()),
+        &["shard"]
+    )
+    .unwrap();
+}
+
+/// renders every registered metric in the prometheus text format
+pub fn render() -> anyhow::Result<String> {
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+pub fn dump(path: &Path) -> anyhow::Result<()> {
+    std::fs::write(path, render()?)?;
+    Ok(())
+}
+
+/// serves the metrics on a background thread for as long as the process lives,
+/// returns the address bound to
+pub fn serve(addr: SocketAddr) -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let bound = listener.local_addr()?;
+    thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || {
+            // note: a slow or idle client only ever holds up its own scrape
+            for stream in listener.incoming().flatten() {
+                thread::spawn(move || respond(stream).ok());
+            }
+        })?;
+    Ok(bound)
+}
+
+fn respond(mut stream: TcpStream) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+    // note: every path gets the metrics, we only need to consume the request head
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line != "\r\n" {
+        line.clear();
+    }
+
+    let body = render()?;
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        TextEncoder::new().format_type(),
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn an_idle_client_does_not_hold_up_a_scrape() {
+        let addr = serve("127.0.0.1:0".parse().unwrap()).unwrap();
+        let _idle = TcpStream::connect(addr).unwrap();
+
+        let mut scrape = TcpStream::connect(addr).unwrap();
+        scrape.set_read_timeout(Some(SCRAPE_TIMEOUT / 5)).unwrap();
+        write!(scrape, "GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        scrape.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    }
+}
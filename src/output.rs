@@ -1,58 +1,410 @@
-use crate::account::*;
-use arrow::array::{BooleanArray, StringArray, UInt16Array};
+use crate::engine::Engine;
+use crate::transaction::ClientId;
+use arrow::array::{ArrayRef, BooleanArray, Decimal128Array, StringArray, UInt64Array};
 use arrow::datatypes::{DataType, Field, Schema};
+use arrow::ipc::writer::FileWriter;
 use arrow::record_batch::RecordBatch;
-use arrow_csv::writer::WriterBuilder;
+use arrow_csv::writer::{Writer, WriterBuilder};
+use clap::ValueEnum;
 use lazy_static::lazy_static;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
 use rust_decimal::Decimal;
-use std::io::stdout;
+use serde::{Deserialize, Serialize};
+use std::io::{Write, stdout};
 use std::sync::Arc;
 
 lazy_static! {
     static ref CSV_SCHEMA_OUTPUT: Schema = Schema::new(vec![
-        Field::new("client", DataType::UInt16, false),
+        Field::new("client", DataType::UInt64, false),
         Field::new("available", DataType::Utf8, false),
         Field::new("held", DataType::Utf8, false),
         Field::new("total", DataType::Utf8, false),
         Field::new("locked", DataType::Boolean, true),
     ]);
+    static ref CSV_SCHEMA_OUTPUT_CREDIT: Schema = Schema::new(
+        CSV_SCHEMA_OUTPUT
+            .fields()
+            .iter()
+            .cloned()
+            .chain([
+                Arc::new(Field::new("credit_limit", DataType::Utf8, false)),
+                Arc::new(Field::new("credit_used", DataType::Utf8, false)),
+            ])
+            .collect::<Vec<_>>()
+    );
+    // note: the arrow and parquet outputs keep amounts as decimals, the csv as text
+    static ref SCHEMA_OUTPUT_TYPED: Schema = Schema::new(
+        CSV_SCHEMA_OUTPUT
+            .fields()
+            .iter()
+            .map(|field| match field.data_type() {
+                DataType::Utf8 => Arc::new(field.as_ref().clone().with_data_type(AMOUNT_TYPE)),
+                _ => field.clone(),
+            })
+            .collect::<Vec<_>>()
+    );
+    static ref SCHEMA_OUTPUT_TYPED_CREDIT: Schema = Schema::new(
+        CSV_SCHEMA_OUTPUT_CREDIT
+            .fields()
+            .iter()
+            .map(|field| match field.data_type() {
+                DataType::Utf8 => Arc::new(field.as_ref().clone().with_data_type(AMOUNT_TYPE)),
+                _ => field.clone(),
+            })
+            .collect::<Vec<_>>()
+    );
 }
 
+// note: wide enough for any amount a `Decimal` holds, always 4 decimal places
+pub(crate) const AMOUNT_TYPE: DataType = DataType::Decimal128(38, 4);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct AccountOutput {
-    pub client: u16,
+    pub client: ClientId,
     pub available: Decimal,
     pub held: Decimal,
     pub total: Decimal,
     pub locked: bool,
+    pub credit_limit: Decimal,
+    /// how far available is below zero
+    pub credit_used: Decimal,
 }
 
-pub fn write_output_accounts(shards: Vec<AccountOutput>) -> anyhow::Result<()> {
-    let mut clients: Vec<u16> = vec![];
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputOrder {
+    /// ascending client id, the same input gives byte identical output for
+    /// any worker count
+    Client,
+    /// shard by shard, clients ascending within each shard
+    Shard,
+    /// each shard's accounts are written as soon as the shard finishes
+    Unsorted,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    Csv,
+    /// one json object per account, amounts are strings to keep them exact
+    Jsonl,
+    /// aligned columns for reading in a terminal
+    Table,
+    /// an arrow ipc file, amounts are decimals with 4 places
+    Arrow,
+    /// a parquet file, amounts are decimals with 4 places
+    Parquet,
+}
+
+/// amounts are always written with 4 decimal places
+pub fn format_amount(amount: Decimal) -> String {
+    format!("{:.4}", amount)
+}
+
+#[derive(Serialize)]
+struct AccountRow {
+    client: ClientId,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credit_limit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credit_used: Option<String>,
+}
+
+impl AccountRow {
+    fn new(account: &AccountOutput, credit: bool) -> Self {
+        AccountRow {
+            client: account.client,
+            available: format_amount(account.available),
+            held: format_amount(account.held),
+            total: format_amount(account.total),
+            locked: account.locked,
+            credit_limit: credit.then(|| format_amount(account.credit_limit)),
+            credit_used: credit.then(|| format_amount(account.credit_used)),
+        }
+    }
+}
+
+enum Sink<W: Write> {
+    Csv(Box<Writer<W>>),
+    Jsonl(W),
+    // note: column widths are only known once every row is in
+    Table(W, Vec<AccountRow>),
+    // note: both file formats end in a footer, so they are written on finish
+    Arrow(W, Vec<AccountOutput>),
+    Parquet(W, Vec<AccountOutput>),
+}
+
+/// writer that accepts accounts in several chunks, headers are only written
+/// once.  `finish` must be called to flush the output
+pub struct AccountWriter<W: Write> {
+    sink: Sink<W>,
+    credit: bool,
+}
+
+impl<W: Write> AccountWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self::with_format(out, OutputFormat::Csv)
+    }
+
+    pub fn with_format(out: W, format: OutputFormat) -> Self {
+        let sink = match format {
+            OutputFormat::Csv => {
+                Sink::Csv(Box::new(WriterBuilder::new().with_header(true).build(out)))
+            }
+            OutputFormat::Jsonl => Sink::Jsonl(out),
+            OutputFormat::Table => Sink::Table(out, vec![]),
+            OutputFormat::Arrow => Sink::Arrow(out, vec![]),
+            OutputFormat::Parquet => Sink::Parquet(out, vec![]),
+        };
+        AccountWriter {
+            sink,
+            credit: false,
+        }
+    }
+
+    /// adds the `credit_limit` and `credit_used` columns
+    pub fn with_credit_columns(mut self, credit: bool) -> Self {
+        self.credit = credit;
+        self
+    }
+
+    pub fn write(&mut self, accounts: &[AccountOutput]) -> anyhow::Result<()> {
+        let credit = self.credit;
+        match &mut self.sink {
+            Sink::Csv(writer) => writer.write(&csv_batch(accounts, credit)?)?,
+            Sink::Jsonl(out) => {
+                for account in accounts {
+                    serde_json::to_writer(&mut *out, &AccountRow::new(account, credit))?;
+                    out.write_all(b"\n")?;
+                }
+            }
+            Sink::Table(_, rows) => rows.extend(
+                accounts
+                    .iter()
+                    .map(|account| AccountRow::new(account, credit)),
+            ),
+            Sink::Arrow(_, rows) | Sink::Parquet(_, rows) => rows.extend_from_slice(accounts),
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> anyhow::Result<()> {
+        let mut out = match self.sink {
+            Sink::Csv(writer) => writer.into_inner(),
+            Sink::Jsonl(out) => out,
+            Sink::Table(mut out, rows) => {
+                write_table(&mut out, &rows)?;
+                out
+            }
+            Sink::Arrow(mut out, rows) => {
+                let batch = typed_batch(&rows, self.credit)?;
+                let mut writer = FileWriter::try_new(&mut out, &batch.schema())?;
+                writer.write(&batch)?;
+                writer.finish()?;
+                drop(writer);
+                out
+            }
+            Sink::Parquet(mut out, rows) => {
+                write_parquet(&typed_batch(&rows, self.credit)?, &mut out)?;
+                out
+            }
+        };
+        out.flush()?;
+        Ok(())
+    }
+}
+
+fn csv_batch(accounts: &[AccountOutput], credit: bool) -> anyhow::Result<RecordBatch> {
+    let mut clients: Vec<ClientId> = vec![];
     let mut available: Vec<String> = vec![];
     let mut held: Vec<String> = vec![];
     let mut total: Vec<String> = vec![];
     let mut locked: Vec<bool> = vec![];
+    let mut credit_limit: Vec<String> = vec![];
+    let mut credit_used: Vec<String> = vec![];
 
-    shards.iter().for_each(|account| {
+    accounts.iter().for_each(|account| {
         clients.push(account.client);
-        available.push(account.available.to_string());
-        held.push(account.held.to_string());
-        total.push(account.total.to_string());
+        available.push(format_amount(account.available));
+        held.push(format_amount(account.held));
+        total.push(format_amount(account.total));
         locked.push(account.locked);
+        if credit {
+            credit_limit.push(format_amount(account.credit_limit));
+            credit_used.push(format_amount(account.credit_used));
+        }
+    });
+
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(UInt64Array::from(clients)),
+        Arc::new(StringArray::from(available)),
+        Arc::new(StringArray::from(held)),
+        Arc::new(StringArray::from(total)),
+        Arc::new(BooleanArray::from(locked)),
+    ];
+    let schema = match credit {
+        true => {
+            columns.push(Arc::new(StringArray::from(credit_limit)));
+            columns.push(Arc::new(StringArray::from(credit_used)));
+            CSV_SCHEMA_OUTPUT_CREDIT.clone()
+        }
+        false => CSV_SCHEMA_OUTPUT.clone(),
+    };
+    Ok(RecordBatch::try_new(Arc::new(schema), columns)?)
+}
+
+/// amounts as an `AMOUNT_TYPE` column, `None` is a null
+pub(crate) fn amount_array(amounts: impl IntoIterator<Item = Option<Decimal>>) -> ArrayRef {
+    let values = amounts.into_iter().map(|amount| {
+        amount.map(|amount| {
+            let mut amount = amount.round_dp(4);
+            amount.rescale(4);
+            amount.mantissa()
+        })
     });
+    Arc::new(Decimal128Array::from_iter(values).with_data_type(AMOUNT_TYPE))
+}
+
+/// writes the batch as a snappy compressed parquet file
+pub(crate) fn write_parquet<W: Write>(batch: &RecordBatch, out: &mut W) -> anyhow::Result<()> {
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    // note: the parquet writer wants a sendable sink, the file is buffered first
+    let mut writer = ArrowWriter::try_new(Vec::new(), batch.schema(), Some(properties))?;
+    writer.write(batch)?;
+    out.write_all(&writer.into_inner()?)?;
+    Ok(())
+}
+
+fn typed_batch(accounts: &[AccountOutput], credit: bool) -> anyhow::Result<RecordBatch> {
+    let amounts = |amount: fn(&AccountOutput) -> Decimal| -> anyhow::Result<ArrayRef> {
+        Ok(amount_array(
+            accounts.iter().map(|account| Some(amount(account))),
+        ))
+    };
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(UInt64Array::from_iter_values(
+            accounts.iter().map(|account| account.client),
+        )),
+        amounts(|account| account.available)?,
+        amounts(|account| account.held)?,
+        amounts(|account| account.total)?,
+        Arc::new(BooleanArray::from_iter(
+            accounts.iter().map(|account| Some(account.locked)),
+        )),
+    ];
+    let schema = match credit {
+        true => {
+            columns.push(amounts(|account| account.credit_limit)?);
+            columns.push(amounts(|account| account.credit_used)?);
+            SCHEMA_OUTPUT_TYPED_CREDIT.clone()
+        }
+        false => SCHEMA_OUTPUT_TYPED.clone(),
+    };
+    Ok(RecordBatch::try_new(Arc::new(schema), columns)?)
+}
+
+fn write_table<W: Write>(out: &mut W, rows: &[AccountRow]) -> anyhow::Result<()> {
+    let credit = rows.first().is_some_and(|row| row.credit_limit.is_some());
+    let mut header = vec!["client", "available", "held", "total", "locked"];
+    if credit {
+        header.extend(["credit_limit", "credit_used"]);
+    }
+    let cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| {
+            let mut cells = vec![
+                row.client.to_string(),
+                row.available.clone(),
+                row.held.clone(),
+                row.total.clone(),
+                row.locked.to_string(),
+            ];
+            cells.extend(row.credit_limit.clone());
+            cells.extend(row.credit_used.clone());
+            cells
+        })
+        .collect();
+
+    let mut widths: Vec<usize> = header.iter().map(|name| name.len()).collect();
+    for row in &cells {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
 
-    let batch = RecordBatch::try_new(
-        Arc::new(CSV_SCHEMA_OUTPUT.clone()),
-        vec![
-            Arc::new(UInt16Array::from(clients)),
-            Arc::new(StringArray::from(available)),
-            Arc::new(StringArray::from(held)),
-            Arc::new(StringArray::from(total)),
-            Arc::new(BooleanArray::from(locked)),
-        ],
-    )?;
-
-    let mut writer = WriterBuilder::new().with_header(true).build(stdout());
-    writer.write(&batch)?;
+    // note: numbers are right aligned, `locked` is the only text column
+    let line = |cells: &[&str]| -> String {
+        cells
+            .iter()
+            .zip(&widths)
+            .enumerate()
+            .map(|(idx, (cell, &width))| match idx == 4 {
+                true => format!("{:<width$}", cell),
+                false => format!("{:>width$}", cell),
+            })
+            .collect::<Vec<String>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    writeln!(out, "{}", line(&header))?;
+    writeln!(out, "{}", rule.join("  "))?;
+    for row in &cells {
+        let row: Vec<&str> = row.iter().map(String::as_str).collect();
+        writeln!(out, "{}", line(&row))?;
+    }
     Ok(())
 }
+
+pub fn write_output_accounts(shards: Vec<AccountOutput>) -> anyhow::Result<()> {
+    write_output_accounts_to(shards, stdout())
+}
+
+pub fn write_output_accounts_to<W: Write>(
+    shards: Vec<AccountOutput>,
+    out: W,
+) -> anyhow::Result<()> {
+    let mut writer = AccountWriter::new(out);
+    writer.write(&shards)?;
+    writer.finish()
+}
+
+/// runs the engine and writes its accounts in the requested order
+pub fn write_engine_output<W: Write>(
+    engine: Engine,
+    order: OutputOrder,
+    mut writer: AccountWriter<W>,
+) -> anyhow::Result<()> {
+    match order {
+        OutputOrder::Client => writer.write(&engine.run()?)?,
+        OutputOrder::Shard => {
+            let mut shards = vec![];
+            engine.run_with(|shard, mut accounts| {
+                accounts.sort_unstable_by_key(|account| account.client);
+                shards.push((shard, accounts));
+                Ok(())
+            })?;
+            shards.sort_unstable_by_key(|(shard, _)| *shard);
+            // note: one write so the header still appears when there are no accounts
+            let accounts: Vec<AccountOutput> = shards
+                .into_iter()
+                .flat_map(|(_, accounts)| accounts)
+                .collect();
+            writer.write(&accounts)?
+        }
+        OutputOrder::Unsorted => {
+            writer.write(&[])?;
+            engine.run_with(|_, accounts| writer.write(&accounts))?
+        }
+    }
+    writer.finish()
+}
@@ -0,0 +1,307 @@
+use crate::account::Account;
+use crate::ledger::LedgerAccount;
+use crate::output::format_amount;
+use crate::shard::{Merge, ShardResults};
+use rust_decimal::Decimal;
+use serde_json::json;
+use std::io::Write;
+use tracing::error;
+
+/// what a run did with the money it was given, summed over every account
+#[derive(Debug, Clone, Default)]
+pub struct Totals {
+    pub accounts: u64,
+    pub locked_accounts: u64,
+    pub applied: u64,
+    pub rejected: u64,
+    pub deposited: Decimal,
+    pub withdrawn: Decimal,
+    pub charged_back: Decimal,
+    pub fees: Decimal,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    /// accounts with negative available and how much credit they draw on,
+    /// a dispute can push available below zero even without a credit line
+    pub overdrawn_accounts: u64,
+    pub credit_used: Decimal,
+    /// overdrawn past their limit, by a dispute or a lowered limit
+    pub over_limit_accounts: u64,
+    /// system account balances summed over every book
+    pub partner_settlement: Decimal,
+    pub chargeback_losses: Decimal,
+    pub fee_revenue: Decimal,
+    /// withdrawals still waiting out their dispute window at shutdown
+    pub pending: u64,
+    pub pending_amount: Decimal,
+    /// per account invariant breaks, found while summing
+    pub violations: Vec<String>,
+}
+
+impl Totals {
+    pub fn add_account(&mut self, account: &Account) {
+        let book = account.book();
+        let flows = account.flows();
+
+        if book.available_funds() + book.held_funds() != book.total_funds() {
+            self.violations.push(format!(
+                "client {}: available {} + held {} != total {}",
+                account.client(),
+                book.available_funds(),
+                book.held_funds(),
+                book.total_funds()
+            ));
+        }
+        let net = flows.deposited - flows.withdrawn - flows.charged_back - flows.fees;
+        if net != book.total_funds() {
+            self.violations.push(format!(
+                "client {}: deposits minus withdrawals, chargebacks and fees is {} but total is {}",
+                account.client(),
+                net,
+                book.total_funds()
+            ));
+        }
+
+        if book.held_funds() != account.disputed_amount() {
+            self.violations.push(format!(
+                "client {}: held is {} but the deposits under dispute amount to {}",
+                account.client(),
+                book.held_funds(),
+                account.disputed_amount()
+            ));
+        }
+
+        if account.credit_used() > Decimal::ZERO {
+            self.overdrawn_accounts += 1;
+            self.credit_used += account.credit_used();
+            if account.credit_used() > account.credit_limit() {
+                self.over_limit_accounts += 1;
+            }
+        }
+
+        self.accounts += 1;
+        if account.locked() {
+            self.locked_accounts += 1;
+        }
+        self.deposited += flows.deposited;
+        self.withdrawn += flows.withdrawn;
+        self.charged_back += flows.charged_back;
+        self.fees += flows.fees;
+        self.available += book.available_funds();
+        self.held += book.held_funds();
+        self.total += book.total_funds();
+        self.partner_settlement += book.balance(LedgerAccount::PartnerSettlement);
+        self.chargeback_losses += book.balance(LedgerAccount::ChargebackLosses);
+        self.fee_revenue += book.balance(LedgerAccount::FeeRevenue);
+    }
+
+    /// every invariant break, the per account ones plus the global ones
+    pub fn check(&self) -> Vec<String> {
+        let mut violations = self.violations.clone();
+        let net = self.deposited - self.withdrawn - self.charged_back - self.fees;
+        if net != self.total {
+            violations.push(format!(
+                "deposits minus withdrawals, chargebacks and fees is {} but the accounts hold {}",
+                net, self.total
+            ));
+        }
+        if self.available + self.held != self.total {
+            violations.push(format!(
+                "available {} + held {} != total {} over all accounts",
+                self.available, self.held, self.total
+            ));
+        }
+        if self.fees != self.fee_revenue {
+            violations.push(format!(
+                "clients were charged {} in fees but fee revenue is {}",
+                self.fees, self.fee_revenue
+            ));
+        }
+        let ledger =
+            self.total + self.partner_settlement + self.chargeback_losses + self.fee_revenue;
+        if !ledger.is_zero() {
+            violations.push(format!(
+                "client and system ledgers are off balance by {}",
+                ledger
+            ));
+        }
+        violations
+    }
+
+    /// fails the run on any violation, balances that do not reconcile must
+    /// never look like a clean run
+    pub fn ensure_balanced(&self) -> anyhow::Result<()> {
+        let violations = self.check();
+        for violation in &violations {
+            error!(violation = %violation, "reconciliation failed");
+        }
+        match violations.is_empty() {
+            true => Ok(()),
+            false => Err(anyhow::anyhow!(
+                "reconciliation failed with {} violations",
+                violations.len()
+            )),
+        }
+    }
+
+    /// writes the summary and any violations as a json object
+    pub fn write<W: Write>(&self, mut out: W) -> anyhow::Result<()> {
+        let report = json!({
+            "accounts": self.accounts,
+            "locked_accounts": self.locked_accounts,
+            "applied": self.applied,
+            "rejected": self.rejected,
+            "deposited": format_amount(self.deposited),
+            "withdrawn": format_amount(self.withdrawn),
+            "charged_back": format_amount(self.charged_back),
+            "fees": format_amount(self.fees),
+            "available": format_amount(self.available),
+            "held": format_amount(self.held),
+            "total": format_amount(self.total),
+            "overdrawn_accounts": self.overdrawn_accounts,
+            "credit_used": format_amount(self.credit_used),
+            "over_limit_accounts": self.over_limit_accounts,
+            "partner_settlement": format_amount(self.partner_settlement),
+            "chargeback_losses": format_amount(self.chargeback_losses),
+            "fee_revenue": format_amount(self.fee_revenue),
+            "pending": self.pending,
+            "pending_amount": format_amount(self.pending_amount),
+            "violations": self.check(),
+        });
+        serde_json::to_writer_pretty(&mut out, &report)?;
+        out.write_all(b"\n")?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// totals of every shard
+pub type Reconciliation = ShardResults<Totals>;
+
+impl Merge for Totals {
+    fn merge(&mut self, other: Totals) {
+        self.accounts += other.accounts;
+        self.locked_accounts += other.locked_accounts;
+        self.applied += other.applied;
+        self.rejected += other.rejected;
+        self.deposited += other.deposited;
+        self.withdrawn += other.withdrawn;
+        self.charged_back += other.charged_back;
+        self.fees += other.fees;
+        self.available += other.available;
+        self.held += other.held;
+        self.total += other.total;
+        self.overdrawn_accounts += other.overdrawn_accounts;
+        self.credit_used += other.credit_used;
+        self.over_limit_accounts += other.over_limit_accounts;
+        self.partner_settlement += other.partner_settlement;
+        self.chargeback_losses += other.chargeback_losses;
+        self.fee_revenue += other.fee_revenue;
+        self.pending += other.pending;
+        self.pending_amount += other.pending_amount;
+        self.violations.extend(other.violations);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fees::{Fee, FeeSchedule};
+    use crate::ledger::{Entry, Posting};
+    use crate::transaction::{Transaction, Tx};
+
+    fn account() -> Account {
+        let mut account = Account::new(1);
+        account.set_fees(FeeSchedule {
+            deposit: Fee {
+                flat: Decimal::ONE,
+                bps: Decimal::ZERO,
+            },
+            ..FeeSchedule::default()
+        });
+        let tx = |id, amount| Tx {
+            client: 1,
+            id,
+            amount: Decimal::from(amount),
+        };
+        account.apply(Transaction::Deposit(tx(1, 10))).unwrap();
+        account.apply(Transaction::Deposit(tx(2, 5))).unwrap();
+        account.apply(Transaction::Dispute(tx(2, 0))).unwrap();
+        account
+    }
+
+    fn post(account: &mut Account, debit: LedgerAccount, credit: LedgerAccount) {
+        account.book_mut().post(Entry {
+            tx: 3,
+            kind: Posting::Dispute,
+            debit,
+            credit,
+            amount: Decimal::from(4),
+        });
+    }
+
+    fn violations(account: &Account) -> Vec<String> {
+        let mut totals = Totals::default();
+        totals.add_account(account);
+        totals.check()
+    }
+
+    #[test]
+    fn a_clean_account_reconciles() {
+        assert_eq!(violations(&account()), Vec::<String>::new());
+        let mut totals = Totals::default();
+        totals.add_account(&account());
+        assert!(totals.ensure_balanced().is_ok());
+    }
+
+    #[test]
+    fn a_misrouted_posting_breaks_available_plus_held() {
+        let mut account = account();
+        post(
+            &mut account,
+            LedgerAccount::Available,
+            LedgerAccount::ChargebackLosses,
+        );
+        let violations = violations(&account);
+        assert!(
+            violations
+                .iter()
+                .any(|v| v.contains("client 1: available 4 + held 5 != total 13")),
+            "{violations:?}"
+        );
+    }
+
+    #[test]
+    fn held_must_match_the_disputed_deposits() {
+        let mut account = account();
+        post(&mut account, LedgerAccount::Available, LedgerAccount::Held);
+        let violations = violations(&account);
+        assert_eq!(violations.len(), 1, "{violations:?}");
+        assert!(violations[0].contains("held is 9 but the deposits under dispute amount to 5"));
+    }
+
+    #[test]
+    fn fee_revenue_and_ledger_mismatches_fail_the_run() {
+        let mut totals = Totals::default();
+        totals.add_account(&account());
+        totals.fee_revenue -= Decimal::ONE;
+        let violations = totals.check();
+        assert!(
+            violations.iter().any(|v| v.contains("fee revenue is 1")),
+            "{violations:?}"
+        );
+        assert!(
+            violations.iter().any(|v| v.contains("off balance by -1")),
+            "{violations:?}"
+        );
+        assert!(totals.ensure_balanced().is_err());
+
+        let mut totals = Totals::default();
+        totals.add_account(&account());
+        totals.partner_settlement += Decimal::ONE;
+        assert_eq!(
+            totals.check(),
+            ["client and system ledgers are off balance by 1"]
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use crate::account::{Account, Rejection};
+use crate::fees::Fees;
+use crate::limits::CreditLimits;
+use crate::output::AccountOutput;
+use crate::retention::Horizon;
+use crate::shard::AccountConfig;
+use crate::transaction::{ClientId, Transaction};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// single threaded model of the engine
+///
+/// every transaction is applied to its `Account` the moment it is seen, with no
+/// shards, channels or pending withdrawal queue.  the sharded engine run with a
+/// zero dispute window and the same settings must agree with it exactly
+pub struct ReferenceModel {
+    config: AccountConfig,
+    accounts: BTreeMap<ClientId, Account>,
+}
+
+impl Default for ReferenceModel {
+    fn default() -> Self {
+        ReferenceModel {
+            config: AccountConfig::for_run(),
+            accounts: BTreeMap::new(),
+        }
+    }
+}
+
+impl ReferenceModel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_credit_limits(mut self, limits: Arc<CreditLimits>) -> Self {
+        self.config.credit_limits = Some(limits);
+        self
+    }
+
+    pub fn with_fees(mut self, fees: Arc<Fees>) -> Self {
+        self.config.fees = Some(fees);
+        self
+    }
+
+    pub fn with_dispute_horizon(mut self, horizon: Horizon) -> Self {
+        self.config.dispute_horizon = Some(horizon);
+        self
+    }
+
+    /// starts from accounts an earlier run left instead of from nothing
+    pub fn with_accounts(mut self, accounts: Vec<Account>) -> Self {
+        self.accounts.extend(
+            accounts
+                .into_iter()
+                .map(|account| (account.client(), account)),
+        );
+        self
+    }
+
+    pub fn apply(&mut self, transaction: Transaction) -> Result<(), Rejection> {
+        let open = self.config.opens(&transaction);
+        self.account(transaction.tx().client, open)?
+            .apply(transaction)
+    }
+
+    fn account(&mut self, client: ClientId, open: bool) -> Result<&mut Account, Rejection> {
+        let account = match open {
+            true => self
+                .accounts
+                .entry(client)
+                .or_insert_with(|| Account::new(client)),
+            false => self
+                .accounts
+                .get_mut(&client)
+                .ok_or(Rejection::UnknownClient)?,
+        };
+        self.config.attach(account);
+        Ok(account)
+    }
+
+    /// final account states ordered by client id
+    pub fn outputs(self) -> Vec<AccountOutput> {
+        self.accounts
+            .into_values()
+            .map(AccountOutput::from)
+            .collect()
+    }
+}
+
+/// applies `transactions` in order and returns the final account states
+pub fn replay(transactions: impl IntoIterator<Item = Transaction>) -> Vec<AccountOutput> {
+    replay_with(ReferenceModel::new(), transactions)
+}
+
+/// as `replay`, on a model set up with credit limits, fees or a dispute horizon
+pub fn replay_with(
+    mut model: ReferenceModel,
+    transactions: impl IntoIterator<Item = Transaction>,
+) -> Vec<AccountOutput> {
+    for transaction in transactions {
+        model.apply(transaction).ok();
+    }
+    model.outputs()
+}
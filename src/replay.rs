@@ -0,0 +1,211 @@
+use crate::account::Account;
+use crate::fees::Fees;
+use crate::io::{InputOrder, merge_transactions, read_transactions};
+use crate::limits::CreditLimits;
+use crate::output::AccountOutput;
+use crate::reference::ReferenceModel;
+use crate::retention::Horizon;
+use crate::transaction::{ClientId, Transaction, TxId};
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use tracing::debug;
+
+/// where a replay stops
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Cutoff {
+    /// just before the first row carrying this tx id, whichever client it is for
+    BeforeTx(TxId),
+    /// after every row stamped at or before this time, the files are merged on
+    /// their `timestamp` column
+    At(u64),
+}
+
+/// what a replay went through to reach its cut-off
+#[derive(Debug, Default, Clone)]
+pub struct ReplaySummary {
+    pub applied: u64,
+    pub rejected: u64,
+    /// rows stamped after the cut-off time
+    pub skipped: u64,
+    /// false when the input ended before the cut-off tx showed up
+    pub reached: bool,
+}
+
+/// the engine run over the input one row at a time, up to a cut-off
+///
+/// rows go through the `ReferenceModel` in input order like the engine with a
+/// zero dispute window, so a withdrawal is applied the moment it is read
+pub struct Replay {
+    cutoff: Cutoff,
+    order: InputOrder,
+    client: Option<ClientId>,
+    model: ReferenceModel,
+    summary: ReplaySummary,
+}
+
+impl Replay {
+    pub fn new(cutoff: Cutoff) -> Self {
+        Replay {
+            cutoff,
+            order: InputOrder::Sequential,
+            client: None,
+            model: ReferenceModel::new(),
+            summary: ReplaySummary::default(),
+        }
+    }
+
+    /// how rows of several files interleave, as for a run.  concurrent files
+    /// are replayed one after another since a replay needs a single order
+    pub fn with_input_order(mut self, order: InputOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// replays only the client's rows, the other accounts can not change it
+    /// and a tx cut-off only matches the client's own rows
+    pub fn with_client(mut self, client: ClientId) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    pub fn with_credit_limits(mut self, limits: Arc<CreditLimits>) -> Self {
+        self.model = self.model.with_credit_limits(limits);
+        self
+    }
+
+    pub fn with_fees(mut self, fees: Arc<Fees>) -> Self {
+        self.model = self.model.with_fees(fees);
+        self
+    }
+
+    pub fn with_dispute_horizon(mut self, horizon: Horizon) -> Self {
+        self.model = self.model.with_dispute_horizon(horizon);
+        self
+    }
+
+    /// starts from accounts a run left in the store instead of from nothing,
+    /// the input is then what came after that run
+    pub fn with_snapshot(mut self, accounts: Vec<Account>) -> Self {
+        self.model = self.model.with_accounts(accounts);
+        self
+    }
+
+    /// applies the row unless it lies past the cut-off, breaks once nothing
+    /// further can be applied
+    pub fn apply(&mut self, timestamp: Option<u64>, transaction: Transaction) -> ControlFlow<()> {
+        let tx = transaction.tx();
+        // note: tx ids are only unique per client, so the filter goes first
+        if self.client.is_some_and(|client| client != tx.client) {
+            return ControlFlow::Continue(());
+        }
+        match (self.cutoff, timestamp) {
+            (Cutoff::BeforeTx(id), _) if tx.id == id => {
+                self.summary.reached = true;
+                return ControlFlow::Break(());
+            }
+            // note: the merge is in timestamp order, but rows going back in
+            //       time may still follow
+            (Cutoff::At(at), Some(timestamp)) if timestamp > at => {
+                self.summary.skipped += 1;
+                return ControlFlow::Continue(());
+            }
+            _ => {}
+        }
+
+        match self.model.apply(transaction) {
+            Ok(()) => self.summary.applied += 1,
+            Err(rejection) => {
+                self.summary.rejected += 1;
+                debug!(
+                    client = tx.client,
+                    tx = tx.id,
+                    reason = rejection.as_str(),
+                    "transaction rejected"
+                );
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// replays the files in the input order, merged on their timestamps for a
+    /// time cut-off, and returns the accounts as they stood at the cut-off,
+    /// ordered by client id
+    pub fn run(mut self, inputs: &[String]) -> anyhow::Result<(Vec<AccountOutput>, ReplaySummary)> {
+        if matches!(self.cutoff, Cutoff::At(_)) || self.order == InputOrder::Timestamp {
+            merge_transactions(inputs, |timestamp, transaction| {
+                self.apply(Some(timestamp), transaction)
+            })?;
+        } else {
+            for input in inputs {
+                read_transactions(input, |timestamp, transaction| {
+                    self.apply(timestamp, transaction)
+                })?;
+                if self.summary.reached {
+                    break;
+                }
+            }
+        }
+        let client = self.client;
+        let outputs = self
+            .model
+            .outputs()
+            .into_iter()
+            .filter(|output| client.is_none_or(|client| client == output.client))
+            .collect();
+        Ok((outputs, self.summary))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn input(rows: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "type,client,tx,amount,timestamp\n{rows}").unwrap();
+        file
+    }
+
+    fn paths(files: &[&NamedTempFile]) -> Vec<String> {
+        files
+            .iter()
+            .map(|file| file.path().to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn time_cutoff_merges_files_on_timestamp() {
+        let a = input("withdraw,1,2,5,20");
+        let b = input("deposit,1,1,10,10");
+        let (accounts, summary) = Replay::new(Cutoff::At(30)).run(&paths(&[&a, &b])).unwrap();
+        assert_eq!(accounts[0].available, Decimal::from(5));
+        assert_eq!(summary.applied, 2);
+
+        let (accounts, summary) = Replay::new(Cutoff::At(15)).run(&paths(&[&a, &b])).unwrap();
+        assert_eq!(accounts[0].available, Decimal::from(10));
+        assert_eq!(summary.skipped, 1);
+    }
+
+    #[test]
+    fn tx_cutoff_only_matches_the_clients_rows() {
+        let a = input("deposit,42,1,10,1\ndeposit,7,5,1,2\ndeposit,42,2,20,3\ndeposit,42,5,30,4");
+        let (accounts, summary) = Replay::new(Cutoff::BeforeTx(5))
+            .with_client(42)
+            .run(&paths(&[&a]))
+            .unwrap();
+        assert!(summary.reached);
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].total, Decimal::from(30));
+    }
+
+    #[test]
+    fn tx_cutoff_stops_before_the_row() {
+        let a = input("deposit,1,1,10,1\ndeposit,1,2,5,2\nwithdraw,1,3,4,3");
+        let (accounts, summary) = Replay::new(Cutoff::BeforeTx(3)).run(&paths(&[&a])).unwrap();
+        assert!(summary.reached);
+        assert_eq!(accounts[0].total, Decimal::from(15));
+    }
+}
@@ -0,0 +1,120 @@
+use crate::assignment::mix;
+use crate::transaction::TxId;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// how long a deposit or withdrawal is kept for disputes and duplicate checks
+///
+/// counted in the transactions the client's account has seen since, which is
+/// deterministic for any worker count
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Horizon {
+    Transactions(u64),
+}
+
+impl fmt::Display for Horizon {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Horizon::Transactions(n) => write!(f, "{}tx", n),
+        }
+    }
+}
+
+impl FromStr for Horizon {
+    type Err = String;
+
+    /// `5000` or `5000tx`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (digits, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+            Some((idx, _)) => s.split_at(idx),
+            None => (s, ""),
+        };
+        let n: u64 = digits
+            .parse()
+            .map_err(|e| format!("bad dispute horizon {:?}: {}", s, e))?;
+        if n == 0 {
+            return Err("dispute horizon must be greater than zero".to_string());
+        }
+        match unit {
+            "" | "tx" => Ok(Horizon::Transactions(n)),
+            // note: rows reach the accounts without their event time, a wall clock
+            // horizon would depend on how fast the input is read
+            "ms" | "s" | "m" | "h" => Err(format!(
+                "dispute horizon {} is a time, it must be a number of transactions",
+                s
+            )),
+            other => Err(format!(
+                "unknown dispute horizon unit {}, expected tx",
+                other
+            )),
+        }
+    }
+}
+
+// note: 4 KiB per account that dropped anything, about two false hits in ten
+// thousand lookups after 1000 dropped ids and two in a thousand after 2000
+const FILTER_WORDS: usize = 512;
+const FILTER_BITS: u64 = FILTER_WORDS as u64 * 64;
+const FILTER_HASHES: u64 = 4;
+
+/// ids of the deposits and withdrawals dropped past the horizon, in a fixed
+/// size however many the client had
+///
+/// nothing above the newest dropped id was dropped, below it a bloom filter
+/// answers.  a dropped id is never missed, an id that was never seen may
+/// rarely be taken for a dropped one once the filter fills up
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EvictedIds {
+    newest: Option<TxId>,
+    // note: allocated on the first eviction
+    filter: Vec<u64>,
+}
+
+impl EvictedIds {
+    // note: double hashing, k probes from the two halves of one mix
+    fn bits(id: TxId) -> impl Iterator<Item = usize> {
+        let hash = mix(id);
+        let (a, b) = (hash & 0xffff_ffff, hash >> 32);
+        (0..FILTER_HASHES).map(move |k| (a.wrapping_add(k.wrapping_mul(b)) % FILTER_BITS) as usize)
+    }
+
+    pub fn contains(&self, id: TxId) -> bool {
+        if self.newest.is_none_or(|newest| id > newest) {
+            return false;
+        }
+        Self::bits(id).all(|bit| self.filter[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    pub fn insert(&mut self, id: TxId) {
+        if self.filter.is_empty() {
+            self.filter = vec![0; FILTER_WORDS];
+        }
+        for bit in Self::bits(id) {
+            self.filter[bit / 64] |= 1 << (bit % 64);
+        }
+        self.newest = self.newest.max(Some(id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicted_ids_stay_a_fixed_size() {
+        let mut evicted = EvictedIds::default();
+        // note: a client's ids interleaved with the other clients', no two consecutive
+        for id in (0..1000).map(|n| n * 7 + 3) {
+            evicted.insert(id);
+        }
+        assert_eq!(evicted.filter.len(), FILTER_WORDS);
+        assert!((0..1000).all(|n| evicted.contains(n * 7 + 3)));
+        assert!(!evicted.contains(7_000));
+        let false_hits = (0..7_000)
+            .filter(|id| id % 7 != 3 && evicted.contains(*id))
+            .count();
+        assert!(false_hits < 10, "{false_hits}");
+    }
+}
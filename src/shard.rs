@@ -1,123 +1,533 @@
-use crate::account::Account;
+use crate::account::{Account, Rejection};
+use crate::fees::Fees;
+use crate::ledger::JournalLog;
+use crate::limits::CreditLimits;
+use crate::metrics;
 use crate::output::AccountOutput;
-use crate::transaction::{PendingWithdraw, Transaction};
+use crate::reconcile::Totals;
+use crate::retention::Horizon;
+use crate::statement::{Clients, StatementLog};
+use crate::store::{AccountStore, FileStore, FileStoreConfig, MemoryStore};
+use crate::transaction::{ClientId, PendingWithdraw, Transaction, Tx, TxBatch};
+use clap::ValueEnum;
 use coarsetime::Clock;
-use crossbeam::channel::Receiver;
-use heapless::Deque;
-use indexmap::IndexMap;
+use crossbeam::channel::{Receiver, TryRecvError};
+use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::sync::Arc;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::hint;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Acquire;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, info_span};
 
-const DISPUTE_WINDOW_MILLISECONDS: u64 = 1;
-const PENDING_QUEUE_SIZE: usize = 256;
+// note: zero keeps every run deterministic, a window trades that for letting
+//       disputes overtake withdrawals by arrival time
+pub const DISPUTE_WINDOW_MILLISECONDS: u64 = 0;
+pub const PENDING_QUEUE_SIZE: usize = 256;
+// empty polls before the yield strategy starts giving up its time slice
+const SPINS_BEFORE_YIELD: u32 = 128;
+// longest a parked worker sleeps, bounds how late it notices the done flag
+const MAX_PARK_MILLISECONDS: u64 = 50;
+
+/// what a worker does when its channel is empty
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IdleStrategy {
+    /// busy poll, lowest latency but a full core per worker
+    Spin,
+    /// busy poll for a while then yield the core to other threads
+    Yield,
+    /// block on the channel until a batch arrives or the next pending
+    /// withdrawal is due
+    Park,
+}
 
 pub struct Worker {
     pub id: u16,
-    pub txs: Receiver<Vec<Transaction>>,
+    pub txs: Receiver<TxBatch>,
+    dispute_window_ms: u64,
+    idle: IdleStrategy,
+    pending_queue: usize,
+    empty_polls: u32,
+    statements: Option<(Arc<Clients>, StatementLog)>,
+    journal: Option<JournalLog>,
+    credit_limits: Option<Arc<CreditLimits>>,
+    fees: Option<Arc<Fees>>,
+    dispute_horizon: Option<Horizon>,
+    store: Option<FileStoreConfig>,
+    totals: Totals,
 }
 
 impl Worker {
-    pub fn new(id: u16, txs: Receiver<Vec<Transaction>>) -> Self {
-        Self { id, txs }
+    pub fn new(
+        id: u16,
+        txs: Receiver<TxBatch>,
+        dispute_window_ms: u64,
+        idle: IdleStrategy,
+    ) -> Self {
+        Self {
+            id,
+            txs,
+            dispute_window_ms,
+            idle,
+            pending_queue: PENDING_QUEUE_SIZE,
+            empty_polls: 0,
+            statements: None,
+            journal: None,
+            credit_limits: None,
+            fees: None,
+            dispute_horizon: None,
+            store: None,
+            totals: Totals::default(),
+        }
     }
 
-    pub fn run(&mut self, done: Arc<AtomicBool>) -> Vec<AccountOutput> {
-        let mut account_shard = AccountShard::new();
+    /// keeps the history of `clients` and hands their statements to `log` once
+    /// the worker stops
+    pub fn with_statements(mut self, clients: Arc<Clients>, log: StatementLog) -> Self {
+        self.statements = Some((clients, log));
+        self
+    }
 
-        loop {
-            if done.load(Acquire) {
-                dbg!("received trigger!");
-                break;
+    /// keeps every account's journal and hands them to `log` once the worker stops
+    pub fn with_journal(mut self, log: JournalLog) -> Self {
+        self.journal = Some(log);
+        self
+    }
+
+    /// accounts start with the credit limit `limits` gives them
+    pub fn with_credit_limits(mut self, limits: Arc<CreditLimits>) -> Self {
+        self.credit_limits = Some(limits);
+        self
+    }
+
+    /// accounts pay the fee schedule of their tier
+    pub fn with_fees(mut self, fees: Arc<Fees>) -> Self {
+        self.fees = Some(fees);
+        self
+    }
+
+    /// accounts forget deposits and withdrawals older than `horizon`
+    pub fn with_dispute_horizon(mut self, horizon: Horizon) -> Self {
+        self.dispute_horizon = Some(horizon);
+        self
+    }
+
+    /// withdrawals that may wait out their dispute window at once, a further
+    /// one holds up the shard until the oldest is due
+    pub fn with_pending_queue(mut self, capacity: usize) -> Self {
+        self.pending_queue = capacity;
+        self
+    }
+
+    /// keeps accounts in the account file instead of memory
+    pub fn with_store(mut self, store: FileStoreConfig) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// what the shard did, filled in once `run` returns
+    pub fn totals(&self) -> &Totals {
+        &self.totals
+    }
+
+    /// called after an empty poll, may hand back a batch that arrived while parked
+    fn wait(&mut self, next_deadline: Option<u64>) -> Option<TxBatch> {
+        match self.idle {
+            IdleStrategy::Spin => {
+                hint::spin_loop();
+                None
             }
+            IdleStrategy::Yield => {
+                self.empty_polls = self.empty_polls.saturating_add(1);
+                if self.empty_polls > SPINS_BEFORE_YIELD {
+                    thread::yield_now();
+                } else {
+                    hint::spin_loop();
+                }
+                None
+            }
+            IdleStrategy::Park => {
+                let now = Clock::now_since_epoch().as_millis();
+                let park_ms = match next_deadline {
+                    Some(deadline) => deadline.saturating_sub(now).min(MAX_PARK_MILLISECONDS),
+                    None => MAX_PARK_MILLISECONDS,
+                };
+                if park_ms == 0 {
+                    return None;
+                }
+                // note: a disconnect is picked up by the next try_recv
+                self.txs.recv_timeout(Duration::from_millis(park_ms)).ok()
+            }
+        }
+    }
 
-            if let Some(ready) = account_shard.ready_withdrawals() {
+    pub fn run(&mut self, done: Arc<AtomicBool>) -> anyhow::Result<Vec<AccountOutput>> {
+        let _span = info_span!("shard", shard = self.id).entered();
+        let mut config = AccountConfig::for_run();
+        let store: Box<dyn AccountStore> = match &self.store {
+            Some(store) => Box::new(FileStore::new(store.clone(), self.id as usize, config.run)),
+            None => Box::new(MemoryStore::default()),
+        };
+        config.history = self.statements.as_ref().map(|(clients, _)| clients.clone());
+        config.journal = self.journal.is_some();
+        config.credit_limits = self.credit_limits.clone();
+        config.fees = self.fees.clone();
+        config.dispute_horizon = self.dispute_horizon;
+        let mut account_shard =
+            AccountShard::new(self.dispute_window_ms, self.pending_queue, store, config);
+
+        let shard = self.id.to_string();
+        let applied = metrics::TRANSACTIONS_APPLIED.with_label_values(&[&shard]);
+        let channel_depth = metrics::CHANNEL_DEPTH.with_label_values(&[&shard]);
+        let pending = metrics::PENDING_WITHDRAWALS.with_label_values(&[&shard]);
+        let locked = metrics::LOCKED_ACCOUNTS.with_label_values(&[&shard]);
+        // note: accounts a store kept locked from an earlier run count too
+        locked.set(account_shard.store.locked()? as i64);
+        let latency = metrics::INGEST_TO_APPLY_SECONDS.with_label_values(&[&shard]);
+        let (applied_count, rejected_count) = (Cell::new(0u64), Cell::new(0u64));
+        let record = |tx: Tx, outcome: Result<(), Rejection>| match outcome {
+            Ok(()) => {
+                applied_count.set(applied_count.get() + 1);
+                applied.inc()
+            }
+            Err(rejection) => {
+                rejected_count.set(rejected_count.get() + 1);
+                debug!(
+                    client = tx.client,
+                    tx = tx.id,
+                    reason = rejection.as_str(),
+                    "transaction rejected"
+                );
+                metrics::ROWS_REJECTED
+                    .with_label_values(&[rejection.as_str()])
+                    .inc()
+            }
+        };
+
+        let settle = |account_shard: &mut AccountShard, now: u64| -> anyhow::Result<()> {
+            while let Some(ready) = account_shard.ready_withdrawals(now) {
                 for pw in ready {
-                    if let Some(account) = account_shard.accounts.get_mut(&pw.tx.client) {
-                        account.borrow_mut().withdraw(pw.tx);
+                    let transaction = Transaction::PendingWithdrawal(pw.tx);
+                    let open = account_shard.config.opens(&transaction);
+                    match account_shard.account(pw.tx.client, open)? {
+                        Some(account) => record(pw.tx, account.apply(transaction)),
+                        None => record(pw.tx, Err(Rejection::UnknownClient)),
                     }
                 }
             }
+            pending.set(account_shard.pending_withdraws.len() as i64);
+            Ok(())
+        };
+
+        loop {
+            if done.load(Acquire) {
+                info!("received shutdown trigger");
+                break;
+            }
+
+            settle(&mut account_shard, Clock::now_since_epoch().as_millis())?;
+
+            let batch = match self.txs.try_recv() {
+                Ok(batch) => batch,
+                Err(TryRecvError::Empty) => match self.wait(account_shard.next_deadline()) {
+                    Some(batch) => batch,
+                    None => continue,
+                },
+                Err(TryRecvError::Disconnected) => {
+                    // note: nothing can overtake the queued withdrawals any more
+                    settle(&mut account_shard, u64::MAX)?;
+                    info!("reader disconnected, shard drained");
+                    break;
+                }
+            };
+            self.empty_polls = 0;
+            channel_depth.set(self.txs.len() as i64);
 
-            match self.txs.try_recv() {
-                Ok(transaction) => match transaction[0] {
-                    Transaction::Deposit(tx) => {
-                        if let Some(account) = account_shard.accounts.get_mut(&tx.client) {
-                            account.borrow_mut().deposit(tx);
-                        } else {
-                            let acc = Rc::new(RefCell::new(Account::new(tx.client)));
-                            acc.borrow_mut().deposit(tx);
-                            account_shard.accounts.insert(tx.client, acc);
+            for transaction in batch.txs {
+                if !account_shard.pending_withdraws.is_empty() {
+                    settle(&mut account_shard, Clock::now_since_epoch().as_millis())?;
+                }
+
+                match transaction {
+                    Transaction::Deposit(tx) | Transaction::CreditLimit(tx) => {
+                        if let Some(account) = account_shard.account(tx.client, true)? {
+                            record(tx, account.apply(transaction));
                         }
                     }
                     Transaction::PendingWithdrawal(tx) => {
                         let arrival_time = Clock::now_since_epoch().as_millis();
-                        let pw = PendingWithdraw {
-                            arrival_time: arrival_time.into(),
-                            tx,
-                        };
-                        account_shard.pending_withdraws.push_back(pw).ok();
-                    }
-                    Transaction::Dispute(tx) => {
-                        if let Some(account) = account_shard.accounts.get_mut(&tx.client) {
-                            account.borrow_mut().dispute(tx);
+                        let mut pw = PendingWithdraw { arrival_time, tx };
+                        // note: a full queue holds up the intake until its oldest
+                        //       withdrawal is due, a withdrawal is never dropped
+                        while let Err(back) = account_shard.queue(pw) {
+                            pw = back;
+                            let now = Clock::now_since_epoch().as_millis();
+                            if let Some(deadline) = account_shard.next_deadline()
+                                && deadline > now
+                            {
+                                debug!(
+                                    client = tx.client,
+                                    tx = tx.id,
+                                    "pending withdrawal queue full, waiting"
+                                );
+                                thread::sleep(Duration::from_millis(deadline - now));
+                            }
+                            settle(&mut account_shard, Clock::now_since_epoch().as_millis())?;
                         }
                     }
-                    Transaction::Resolve(tx) => {
-                        if let Some(account) = account_shard.accounts.get_mut(&tx.client) {
-                            account.borrow_mut().resolve(tx);
+                    Transaction::Dispute(tx) => match account_shard.account(tx.client, false)? {
+                        Some(account) => record(tx, account.apply(transaction)),
+                        None => record(tx, Err(Rejection::UnknownClient)),
+                    },
+                    Transaction::Resolve(tx) => match account_shard.account(tx.client, false)? {
+                        Some(account) => record(tx, account.apply(transaction)),
+                        None => record(tx, Err(Rejection::UnknownClient)),
+                    },
+                    Transaction::Chargeback(tx) => match account_shard.account(tx.client, false)? {
+                        Some(account) => {
+                            let was_locked = account.locked();
+                            let outcome = account.apply(transaction);
+                            if !was_locked && account.locked() {
+                                locked.inc();
+                            }
+                            record(tx, outcome);
                         }
-                    }
-                    Transaction::Chargeback(tx) => {
-                        if let Some(account) = account_shard.accounts.get_mut(&tx.client) {
-                            account.borrow_mut().chargeback(tx);
-                        }
-                    }
-                },
-                Err(_) => continue,
+                        None => record(tx, Err(Rejection::UnknownClient)),
+                    },
+                }
             }
+            pending.set(account_shard.pending_withdraws.len() as i64);
+            latency.observe(batch.ingested_at.elapsed().as_secs_f64());
         }
 
-        account_shard
-            .accounts
-            .iter()
-            .map(|(_, account)| {
-                let account = account.borrow();
-                AccountOutput {
-                    client: account.client(),
-                    available: account.book().available_funds,
-                    held: account.book().held_funds,
-                    total: account.book().total_funds,
-                    locked: account.locked(),
-                }
-            })
-            .collect::<Vec<AccountOutput>>()
+        let mut totals = Totals {
+            applied: applied_count.get(),
+            rejected: rejected_count.get(),
+            pending: account_shard.pending_withdraws.len() as u64,
+            pending_amount: account_shard
+                .pending_withdraws
+                .iter()
+                .map(|pw| pw.tx.amount)
+                .sum(),
+            ..Totals::default()
+        };
+        let (mut journals, mut statements, mut outputs) = (vec![], vec![], vec![]);
+        let config = &account_shard.config;
+        let run = config.run;
+        account_shard.store.for_each(&mut |account| {
+            // note: a revoked or lowered line reaches stored accounts with no rows
+            //       in this run too, which makes them this run's to write back
+            if config.limit_changed(account) {
+                config.attach(account);
+            }
+            totals.add_account(account);
+            // note: accounts this run never touched have nothing to hand out
+            if account.attached() == run {
+                journals.extend(account.take_journal());
+                statements.extend(account.take_statement());
+            }
+            outputs.push(AccountOutput::from(&*account));
+        })?;
+        account_shard.store.flush()?;
+        self.totals = totals;
+
+        if let Some(log) = &self.journal {
+            log.add(journals);
+        }
+        if let Some((_, log)) = &self.statements {
+            log.add(statements);
+        }
+        Ok(outputs)
+    }
+}
+
+/// a shard's share of a result, merged with the other shards' shares
+pub trait Merge: Default {
+    fn merge(&mut self, other: Self);
+}
+
+/// results collected from every shard as the shards finish
+pub struct ShardResults<T> {
+    merged: Arc<Mutex<T>>,
+}
+
+impl<T: Merge> Default for ShardResults<T> {
+    fn default() -> Self {
+        ShardResults {
+            merged: Arc::default(),
+        }
+    }
+}
+
+impl<T> Clone for ShardResults<T> {
+    fn clone(&self) -> Self {
+        ShardResults {
+            merged: self.merged.clone(),
+        }
+    }
+}
+
+impl<T: Merge> ShardResults<T> {
+    pub fn add(&self, part: T) {
+        if let Ok(mut merged) = self.merged.lock() {
+            merged.merge(part);
+        }
+    }
+
+    /// everything collected so far
+    pub fn take(&self) -> T {
+        self.merged
+            .lock()
+            .map(|mut merged| std::mem::take(&mut *merged))
+            .unwrap_or_default()
+    }
+}
+
+/// how this run sets up the accounts it touches
+#[derive(Default)]
+pub(crate) struct AccountConfig {
+    // note: tells accounts configured by this run from ones the store kept
+    //       from an earlier run
+    pub(crate) run: u64,
+    pub(crate) history: Option<Arc<Clients>>,
+    pub(crate) journal: bool,
+    pub(crate) credit_limits: Option<Arc<CreditLimits>>,
+    pub(crate) fees: Option<Arc<Fees>>,
+    pub(crate) dispute_horizon: Option<Horizon>,
+}
+
+impl AccountConfig {
+    /// no settings yet, stamped with the time the run started
+    pub(crate) fn for_run() -> Self {
+        let run = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |since| since.as_nanos() as u64);
+        AccountConfig {
+            run,
+            ..AccountConfig::default()
+        }
+    }
+
+    fn has_credit(&self, client: ClientId) -> bool {
+        self.credit_limits
+            .as_ref()
+            .is_some_and(|limits| !limits.get(client).is_zero())
+    }
+
+    /// whether the transaction opens an account for a client not seen yet
+    pub(crate) fn opens(&self, transaction: &Transaction) -> bool {
+        match transaction {
+            Transaction::Deposit(_) | Transaction::CreditLimit(_) => true,
+            // note: a client with a credit line may withdraw before any deposit
+            Transaction::PendingWithdrawal(tx) => self.has_credit(tx.client),
+            _ => false,
+        }
+    }
+
+    /// whether the limits file moved the credit line of an account this run has
+    /// not touched
+    pub(crate) fn limit_changed(&self, account: &Account) -> bool {
+        account.attached() != self.run
+            && self
+                .credit_limits
+                .as_ref()
+                .is_some_and(|limits| limits.get(account.client()) != account.credit_limit())
+    }
+
+    /// applies this run's settings the first time the run touches `account`
+    pub(crate) fn attach(&self, account: &mut Account) {
+        if account.attached() == self.run {
+            return;
+        }
+        let client = account.client();
+        // note: the limits file is the full list on every run, admin rows in the
+        //       input override it from then on
+        if let Some(limits) = &self.credit_limits {
+            account.set_credit_limit(limits.get(client)).ok();
+        }
+        account.take_statement();
+        if self
+            .history
+            .as_ref()
+            .is_some_and(|clients| clients.contains(client))
+        {
+            account.keep_history();
+        }
+        account.take_journal();
+        if self.journal {
+            account.keep_journal();
+        }
+        account.set_fees(
+            self.fees
+                .as_ref()
+                .map(|fees| fees.schedule(client))
+                .unwrap_or_default(),
+        );
+        account.set_horizon(self.dispute_horizon);
+        account.attach(self.run);
     }
 }
 
 struct AccountShard {
-    pending_withdraws: Deque<PendingWithdraw, PENDING_QUEUE_SIZE>,
-    accounts: IndexMap<u16, Rc<RefCell<Account>>>,
+    dispute_window_ms: u64,
+    pending_queue: usize,
+    pending_withdraws: VecDeque<PendingWithdraw>,
+    store: Box<dyn AccountStore>,
+    config: AccountConfig,
 }
 
 impl AccountShard {
-    fn new() -> Self {
+    fn new(
+        dispute_window_ms: u64,
+        pending_queue: usize,
+        store: Box<dyn AccountStore>,
+        config: AccountConfig,
+    ) -> Self {
         AccountShard {
-            pending_withdraws: Deque::new(),
-            accounts: IndexMap::new(),
+            dispute_window_ms,
+            pending_queue,
+            pending_withdraws: VecDeque::with_capacity(pending_queue),
+            store,
+            config,
+        }
+    }
+
+    /// the client's account, opened first when `open` is set and it does not exist yet
+    fn account(&mut self, client: ClientId, open: bool) -> anyhow::Result<Option<&mut Account>> {
+        let mut account = self.store.get(client, open)?;
+        if let Some(account) = account.as_deref_mut() {
+            self.config.attach(account);
         }
+        Ok(account)
     }
 
-    fn ready_withdrawals(&mut self) -> Option<SmallVec<[PendingWithdraw; 10]>> {
-        let now = Clock::now_since_epoch().as_millis();
+    /// holds a withdrawal back for its dispute window, hands it back when the queue is full
+    fn queue(&mut self, pw: PendingWithdraw) -> Result<(), PendingWithdraw> {
+        if self.pending_withdraws.len() >= self.pending_queue {
+            return Err(pw);
+        }
+        self.pending_withdraws.push_back(pw);
+        Ok(())
+    }
 
+    /// when the oldest pending withdrawal leaves its dispute window
+    fn next_deadline(&self) -> Option<u64> {
+        self.pending_withdraws
+            .front()
+            .map(|pw| pw.arrival_time.saturating_add(self.dispute_window_ms))
+    }
+
+    /// pops up to 10 withdrawals whose dispute window has passed at `now`
+    fn ready_withdrawals(&mut self, now: u64) -> Option<SmallVec<[PendingWithdraw; 10]>> {
         let mut ready: SmallVec<[PendingWithdraw; 10]> = SmallVec::new();
         while ready.len() < 10 {
             match self.pending_withdraws.front() {
-                Some(pw) if now >= pw.arrival_time + DISPUTE_WINDOW_MILLISECONDS => {
+                Some(pw) if now >= pw.arrival_time.saturating_add(self.dispute_window_ms) => {
                     if let Some(pw) = self.pending_withdraws.pop_front() {
                         ready.push(pw);
                     }
@@ -126,6 +536,43 @@ impl AccountShard {
             }
         }
 
-        Some(ready)
+        if ready.is_empty() { None } else { Some(ready) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+    use std::io::Write;
+
+    fn limits(rows: &str) -> Arc<CreditLimits> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "client,limit\n{rows}").unwrap();
+        Arc::new(CreditLimits::load(file.path()).unwrap())
+    }
+
+    #[test]
+    fn the_limits_file_reaches_stored_accounts() {
+        let mut first = AccountConfig::for_run();
+        first.credit_limits = Some(limits("1,100"));
+        let mut account = Account::new(1);
+        first.attach(&mut account);
+        assert_eq!(account.credit_limit(), Decimal::from(100));
+
+        // note: the next run revoked the line
+        let mut next = AccountConfig::for_run();
+        next.run = first.run + 1;
+        next.credit_limits = Some(limits("2,5"));
+        assert!(next.limit_changed(&account));
+        next.attach(&mut account);
+        assert_eq!(account.credit_limit(), Decimal::ZERO);
+        assert!(!next.limit_changed(&account));
+
+        // note: an admin row overrides the file for the rest of the run
+        account.set_credit_limit(Decimal::from(30)).unwrap();
+        next.attach(&mut account);
+        assert_eq!(account.credit_limit(), Decimal::from(30));
+        assert!(!next.limit_changed(&account));
     }
 }
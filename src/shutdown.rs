@@ -0,0 +1,48 @@
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+use std::sync::Arc;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+
+// note: exit codes of a process killed by a signal, 128 plus the signal number
+const SIGNAL_EXIT_BASE: i32 = 128;
+
+/// SIGINT and SIGTERM stop the intake, the shards then drain and the output is
+/// written as usual.  a second signal exits on the spot
+#[derive(Clone)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    signal: Arc<AtomicUsize>,
+}
+
+impl Shutdown {
+    pub fn install() -> anyhow::Result<Self> {
+        let requested = Arc::new(AtomicBool::new(false));
+        let signal = Arc::new(AtomicUsize::new(0));
+        for sig in [SIGINT, SIGTERM] {
+            // note: registered first so it sees the flag before this signal sets it
+            flag::register_conditional_shutdown(sig, SIGNAL_EXIT_BASE + sig, requested.clone())?;
+            flag::register(sig, requested.clone())?;
+            flag::register_usize(sig, signal.clone(), sig as usize)?;
+        }
+        Ok(Shutdown { requested, signal })
+    }
+
+    /// set once a signal asked the engine to stop
+    pub fn requested(&self) -> Arc<AtomicBool> {
+        self.requested.clone()
+    }
+
+    /// the signal that asked the engine to stop, if any
+    pub fn signal(&self) -> Option<i32> {
+        match self.signal.load(Relaxed) {
+            0 => None,
+            sig => Some(sig as i32),
+        }
+    }
+
+    /// what the process exits with after a signal asked it to stop
+    pub fn exit_code(&self) -> Option<u8> {
+        self.signal().map(|sig| (SIGNAL_EXIT_BASE + sig) as u8)
+    }
+}
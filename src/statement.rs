@@ -0,0 +1,298 @@
+use crate::account::Rejection;
+use crate::output::{AMOUNT_TYPE, amount_array, write_parquet};
+use crate::shard::{Merge, ShardResults};
+use crate::topology::parse_ranges;
+use crate::transaction::{ClientId, Transaction, TxId};
+use arrow::array::{ArrayRef, BooleanArray, RecordBatch, StringArray, UInt64Array};
+use arrow::datatypes::{DataType, Field, Schema};
+use clap::ValueEnum;
+use lazy_static::lazy_static;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// which clients get a statement, `all` or a list such as `1,7,100-200`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Clients {
+    All,
+    // note: kept as ranges, `0-18446744073709551615` is a valid list
+    Only(Vec<RangeInclusive<ClientId>>),
+}
+
+impl Clients {
+    pub fn contains(&self, client: ClientId) -> bool {
+        match self {
+            Clients::All => true,
+            Clients::Only(ranges) => ranges.iter().any(|range| range.contains(&client)),
+        }
+    }
+}
+
+impl FromStr for Clients {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().eq_ignore_ascii_case("all") {
+            return Ok(Clients::All);
+        }
+        parse_ranges(s, "client").map(Clients::Only)
+    }
+}
+
+/// one transaction as seen by an account, with the balances right after it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatementEntry {
+    pub transaction: Transaction,
+    pub rejection: Option<Rejection>,
+    /// fees charged by the transaction, negative when a fee was refunded
+    pub fee: Decimal,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Statement {
+    pub client: ClientId,
+    pub entries: Vec<StatementEntry>,
+}
+
+/// statements of every shard, ordered by client id
+pub type StatementLog = ShardResults<Vec<Statement>>;
+
+impl Merge for Vec<Statement> {
+    fn merge(&mut self, other: Self) {
+        self.extend(other);
+        self.sort_unstable_by_key(|statement| statement.client);
+    }
+}
+
+/// how statements are written
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatementFormat {
+    Csv,
+    /// amounts are decimals with 4 places, `amount` and `reason` may be null
+    Parquet,
+}
+
+lazy_static! {
+    static ref STATEMENT_SCHEMA: Schema = Schema::new(vec![
+        Field::new("client", DataType::UInt64, false),
+        Field::new("seq", DataType::UInt64, false),
+        Field::new("type", DataType::Utf8, false),
+        Field::new("tx", DataType::UInt64, false),
+        Field::new("amount", AMOUNT_TYPE, true),
+        Field::new("status", DataType::Utf8, false),
+        Field::new("reason", DataType::Utf8, true),
+        Field::new("fee", AMOUNT_TYPE, false),
+        Field::new("available", AMOUNT_TYPE, false),
+        Field::new("held", AMOUNT_TYPE, false),
+        Field::new("total", AMOUNT_TYPE, false),
+        Field::new("locked", DataType::Boolean, false),
+    ]);
+}
+
+#[derive(Serialize)]
+struct StatementRow {
+    client: ClientId,
+    seq: u64,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    tx: TxId,
+    #[serde(serialize_with = "text::optional")]
+    amount: Option<Decimal>,
+    status: &'static str,
+    reason: Option<&'static str>,
+    #[serde(serialize_with = "text::amount")]
+    fee: Decimal,
+    #[serde(serialize_with = "text::amount")]
+    available: Decimal,
+    #[serde(serialize_with = "text::amount")]
+    held: Decimal,
+    #[serde(serialize_with = "text::amount")]
+    total: Decimal,
+    locked: bool,
+}
+
+fn statement_rows(statements: &[Statement]) -> impl Iterator<Item = StatementRow> + '_ {
+    statements.iter().flat_map(|statement| {
+        statement.entries.iter().zip(1..).map(|(entry, seq)| {
+            let tx = entry.transaction.tx();
+            let amount = match entry.transaction {
+                Transaction::Deposit(_)
+                | Transaction::PendingWithdrawal(_)
+                | Transaction::CreditLimit(_) => Some(tx.amount),
+                _ => None,
+            };
+            StatementRow {
+                client: statement.client,
+                seq,
+                kind: entry.transaction.kind(),
+                tx: tx.id,
+                amount,
+                status: if entry.rejection.is_some() {
+                    "rejected"
+                } else {
+                    "applied"
+                },
+                reason: entry.rejection.map(|rejection| rejection.as_str()),
+                fee: entry.fee,
+                available: entry.available,
+                held: entry.held,
+                total: entry.total,
+                locked: entry.locked,
+            }
+        })
+    })
+}
+
+/// writes one row per statement entry, entries keep the order they were
+/// applied in
+pub fn write_statements<W: Write>(
+    statements: &[Statement],
+    format: StatementFormat,
+    mut out: W,
+) -> anyhow::Result<()> {
+    match format {
+        StatementFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            for row in statement_rows(statements) {
+                writer.serialize(row)?;
+            }
+            writer.flush()?;
+        }
+        StatementFormat::Parquet => {
+            let rows: Vec<StatementRow> = statement_rows(statements).collect();
+            let columns: Vec<ArrayRef> = vec![
+                Arc::new(UInt64Array::from_iter_values(
+                    rows.iter().map(|row| row.client),
+                )),
+                Arc::new(UInt64Array::from_iter_values(
+                    rows.iter().map(|row| row.seq),
+                )),
+                Arc::new(StringArray::from_iter_values(
+                    rows.iter().map(|row| row.kind),
+                )),
+                Arc::new(UInt64Array::from_iter_values(rows.iter().map(|row| row.tx))),
+                amount_array(rows.iter().map(|row| row.amount)),
+                Arc::new(StringArray::from_iter_values(
+                    rows.iter().map(|row| row.status),
+                )),
+                Arc::new(StringArray::from_iter(rows.iter().map(|row| row.reason))),
+                amount_array(rows.iter().map(|row| Some(row.fee))),
+                amount_array(rows.iter().map(|row| Some(row.available))),
+                amount_array(rows.iter().map(|row| Some(row.held))),
+                amount_array(rows.iter().map(|row| Some(row.total))),
+                Arc::new(BooleanArray::from_iter(
+                    rows.iter().map(|row| Some(row.locked)),
+                )),
+            ];
+            let batch = RecordBatch::try_new(Arc::new(STATEMENT_SCHEMA.clone()), columns)?;
+            write_parquet(&batch, &mut out)?;
+            out.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// amounts in the csv are written as everywhere else, i.e. `1.5000`
+mod text {
+    use crate::output::format_amount;
+    use rust_decimal::Decimal;
+    use serde::Serializer;
+
+    pub fn amount<S: Serializer>(amount: &Decimal, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_amount(*amount))
+    }
+
+    pub fn optional<S: Serializer>(
+        amount: &Option<Decimal>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match amount {
+            Some(amount) => self::amount(amount, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_lists_are_kept_as_ranges() {
+        let clients: Clients = "1, 7,100-200".parse().unwrap();
+        assert!(clients.contains(7) && clients.contains(150) && !clients.contains(201));
+        let every: Clients = "0-18446744073709551615".parse().unwrap();
+        assert!(every.contains(ClientId::MAX));
+        assert_eq!("all".parse::<Clients>().unwrap(), Clients::All);
+
+        for (list, error) in [
+            ("5-1", "empty client range 5-1"),
+            ("", "client list is empty"),
+            ("x", "bad client id"),
+        ] {
+            let parsed = list.parse::<Clients>().unwrap_err();
+            assert!(parsed.starts_with(error), "{parsed}");
+        }
+        assert_eq!(
+            crate::topology::parse_core_list("0-2,6").unwrap(),
+            [0, 1, 2, 6]
+        );
+    }
+
+    #[test]
+    fn parquet_statements_keep_decimals_and_nulls() {
+        use crate::transaction::Tx;
+        use arrow::array::{Array, AsArray, Decimal128Array};
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let entry = |transaction, rejection| StatementEntry {
+            transaction,
+            rejection,
+            fee: Decimal::ZERO,
+            available: Decimal::new(15, 1),
+            held: Decimal::ZERO,
+            total: Decimal::new(15, 1),
+            locked: false,
+        };
+        let tx = Tx {
+            client: 3,
+            id: 1,
+            amount: Decimal::new(15, 1),
+        };
+        let statements = [Statement {
+            client: 3,
+            entries: vec![
+                entry(Transaction::Deposit(tx), None),
+                entry(Transaction::Dispute(tx), Some(Rejection::UnknownTx)),
+            ],
+        }];
+        let file = tempfile::NamedTempFile::new().unwrap();
+        write_statements(
+            &statements,
+            StatementFormat::Parquet,
+            file.reopen().unwrap(),
+        )
+        .unwrap();
+
+        let mut batches = ParquetRecordBatchReaderBuilder::try_new(file.reopen().unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let batch = batches.next().unwrap().unwrap();
+        assert_eq!(batch.num_rows(), 2);
+        let amounts: &Decimal128Array = batch.column_by_name("amount").unwrap().as_primitive();
+        assert_eq!(amounts.value(0), 15_000);
+        assert!(amounts.is_null(1));
+        let reasons = batch.column_by_name("reason").unwrap().as_string::<i32>();
+        assert!(reasons.is_null(0));
+        assert_eq!(reasons.value(1), Rejection::UnknownTx.as_str());
+    }
+}
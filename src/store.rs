@@ -0,0 +1,430 @@
+use crate::account::Account;
+use crate::assignment::ShardAssignment;
+use crate::transaction::ClientId;
+use indexmap::IndexMap;
+use redb::{
+    Database, Durability, ReadOnlyDatabase, ReadableDatabase, ReadableTable, TableDefinition,
+    TableHandle,
+};
+use std::fmt;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+
+const ACCOUNTS: TableDefinition<u64, &[u8]> = TableDefinition::new("accounts");
+// note: the locked clients, so a shard counts them without decoding every account
+const LOCKED: TableDefinition<u64, ()> = TableDefinition::new("locked");
+pub const STORE_CACHE_ACCOUNTS: usize = 100_000;
+
+/// where a shard keeps its accounts
+pub trait AccountStore {
+    /// the client's account, opened first when `open` is set and it does not exist yet
+    fn get(&mut self, client: ClientId, open: bool) -> anyhow::Result<Option<&mut Account>>;
+
+    /// visits every account the shard owns, changes are kept
+    fn for_each(&mut self, f: &mut dyn FnMut(&mut Account)) -> anyhow::Result<()>;
+
+    /// makes every change so far durable
+    fn flush(&mut self) -> anyhow::Result<()>;
+
+    /// how many of the shard's accounts are locked
+    fn locked(&self) -> anyhow::Result<u64>;
+}
+
+/// accounts on the worker's heap, gone when the process exits
+#[derive(Default)]
+pub struct MemoryStore {
+    accounts: IndexMap<ClientId, Account>,
+}
+
+impl AccountStore for MemoryStore {
+    fn get(&mut self, client: ClientId, open: bool) -> anyhow::Result<Option<&mut Account>> {
+        if open {
+            return Ok(Some(
+                self.accounts
+                    .entry(client)
+                    .or_insert_with(|| Account::new(client)),
+            ));
+        }
+        Ok(self.accounts.get_mut(&client))
+    }
+
+    fn for_each(&mut self, f: &mut dyn FnMut(&mut Account)) -> anyhow::Result<()> {
+        self.accounts.values_mut().for_each(f);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn locked(&self) -> anyhow::Result<u64> {
+        Ok(self.accounts.values().filter(|a| a.locked()).count() as u64)
+    }
+}
+
+/// what a shard needs to keep its accounts in the account file
+#[derive(Clone)]
+pub struct FileStoreConfig {
+    pub db: Arc<Database>,
+    /// accounts each shard keeps in memory
+    pub cache_accounts: usize,
+    /// tells the shards which of the stored accounts are theirs
+    pub assignment: Arc<dyn ShardAssignment>,
+}
+
+impl fmt::Debug for FileStoreConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileStoreConfig")
+            .field("cache_accounts", &self.cache_accounts)
+            .finish_non_exhaustive()
+    }
+}
+
+/// opens the account file every shard shares, creating it when missing
+pub fn open(path: &Path) -> anyhow::Result<Arc<Database>> {
+    let db = Database::create(path)
+        .map_err(|e| anyhow::anyhow!("failed to open account store {}: {e}", path.display()))?;
+    let txn = db.begin_write()?;
+    let indexed = txn
+        .list_tables()?
+        .any(|table| table.name() == LOCKED.name());
+    {
+        let accounts = txn.open_table(ACCOUNTS)?;
+        let mut locked = txn.open_table(LOCKED)?;
+        // note: a file written before the locked table existed is indexed once
+        if !indexed {
+            for row in accounts.iter()? {
+                let (client, bytes) = row?;
+                if postcard::from_bytes::<Account>(bytes.value())?.locked() {
+                    locked.insert(client.value(), ())?;
+                }
+            }
+        }
+    }
+    txn.commit()?;
+    Ok(Arc::new(db))
+}
+
+/// the accounts of an account file as a run left them, or only the client's.
+/// the file is opened read only and never changed
+pub fn snapshot(path: &Path, client: Option<ClientId>) -> anyhow::Result<Vec<Account>> {
+    let db = ReadOnlyDatabase::open(path)
+        .map_err(|e| anyhow::anyhow!("failed to open account store {}: {e}", path.display()))?;
+    let txn = db.begin_read()?;
+    let table = txn.open_table(ACCOUNTS)?;
+    let decode = |bytes: &[u8]| -> anyhow::Result<Account> { Ok(postcard::from_bytes(bytes)?) };
+    match client {
+        Some(client) => match table.get(client)? {
+            Some(bytes) => Ok(vec![decode(bytes.value())?]),
+            None => Ok(vec![]),
+        },
+        None => table.iter()?.map(|row| decode(row?.1.value())).collect(),
+    }
+}
+
+/// accounts in an embedded key value file shared by every shard, each shard
+/// caches the accounts it touched last
+///
+/// accounts outlive the process and may outgrow memory, the cache is written
+/// back in bulk when full and on `flush`
+pub struct FileStore {
+    db: Arc<Database>,
+    shard: usize,
+    // note: accounts attached to another run were not changed by this one
+    run: u64,
+    assignment: Arc<dyn ShardAssignment>,
+    capacity: usize,
+    cache: IndexMap<ClientId, Account>,
+}
+
+impl FileStore {
+    /// the store of `shard` for the run stamped `run`
+    pub fn new(config: FileStoreConfig, shard: usize, run: u64) -> Self {
+        FileStore {
+            db: config.db,
+            shard,
+            run,
+            assignment: config.assignment,
+            capacity: config.cache_accounts.max(1),
+            cache: IndexMap::new(),
+        }
+    }
+
+    fn load(&self, client: ClientId) -> anyhow::Result<Option<Account>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(ACCOUNTS)?;
+        match table.get(client)? {
+            Some(bytes) => Ok(Some(postcard::from_bytes(bytes.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    // note: durability is left to `flush`, a crash loses what was written since
+    fn write_back(
+        &self,
+        accounts: impl Iterator<Item = (ClientId, Account)>,
+    ) -> anyhow::Result<()> {
+        let mut txn = self.db.begin_write()?;
+        txn.set_durability(Durability::None)?;
+        {
+            let mut table = txn.open_table(ACCOUNTS)?;
+            let mut locked = txn.open_table(LOCKED)?;
+            for (client, account) in accounts {
+                table.insert(client, postcard::to_stdvec(&account)?.as_slice())?;
+                match account.locked() {
+                    true => locked.insert(client, ())?,
+                    false => locked.remove(client)?,
+                };
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// writes back the older half of the cache once it is full
+    fn make_room(&mut self) -> anyhow::Result<()> {
+        if self.cache.len() < self.capacity {
+            return Ok(());
+        }
+        let evict = self.cache.len().div_ceil(2);
+        let accounts: Vec<_> = self.cache.drain(..evict).collect();
+        self.write_back(accounts.into_iter())
+    }
+}
+
+impl AccountStore for FileStore {
+    fn get(&mut self, client: ClientId, open: bool) -> anyhow::Result<Option<&mut Account>> {
+        if !self.cache.contains_key(&client) {
+            let account = match self.load(client)? {
+                Some(account) => account,
+                None if open => Account::new(client),
+                None => return Ok(None),
+            };
+            self.make_room()?;
+            self.cache.insert(client, account);
+        }
+        Ok(self.cache.get_mut(&client))
+    }
+
+    fn for_each(&mut self, f: &mut dyn FnMut(&mut Account)) -> anyhow::Result<()> {
+        let cached = std::mem::take(&mut self.cache);
+        self.write_back(cached.into_iter())?;
+
+        // note: walks the file a cache worth of accounts at a time
+        let mut after = Bound::Unbounded;
+        loop {
+            let mut chunk = vec![];
+            {
+                let txn = self.db.begin_read()?;
+                let table = txn.open_table(ACCOUNTS)?;
+                for row in table.range::<u64>((after, Bound::Unbounded))? {
+                    let (client, bytes) = row?;
+                    let client = client.value();
+                    after = Bound::Excluded(client);
+                    if self.assignment.shard(client) != self.shard {
+                        continue;
+                    }
+                    chunk.push((client, postcard::from_bytes::<Account>(bytes.value())?));
+                    if chunk.len() == self.capacity {
+                        break;
+                    }
+                }
+            }
+            if chunk.is_empty() {
+                return Ok(());
+            }
+            for (_, account) in chunk.iter_mut() {
+                f(account);
+            }
+            let run = self.run;
+            self.write_back(
+                chunk
+                    .into_iter()
+                    .filter(|(_, account)| account.attached() == run),
+            )?;
+        }
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        let cached = std::mem::take(&mut self.cache);
+        self.write_back(cached.into_iter())?;
+        // note: an immediate commit makes the earlier non durable ones durable too
+        let txn = self.db.begin_write()?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    fn locked(&self) -> anyhow::Result<u64> {
+        let mut locked = self.cache.values().filter(|a| a.locked()).count() as u64;
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(LOCKED)?;
+        for row in table.iter()? {
+            let client = row?.0.value();
+            if self.assignment.shard(client) == self.shard && !self.cache.contains_key(&client) {
+                locked += 1;
+            }
+        }
+        Ok(locked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assignment::Modulo;
+    use crate::transaction::{Transaction, Tx};
+    use rust_decimal::Decimal;
+    use tempfile::TempDir;
+
+    const RUN: u64 = 1;
+
+    fn store(db: &Arc<Database>, cache: usize, shards: usize, shard: usize, run: u64) -> FileStore {
+        let config = FileStoreConfig {
+            db: db.clone(),
+            cache_accounts: cache,
+            assignment: Arc::new(Modulo::new(shards)),
+        };
+        FileStore::new(config, shard, run)
+    }
+
+    fn deposit(store: &mut FileStore, client: ClientId, amount: i64) {
+        let account = store.get(client, true).unwrap().unwrap();
+        account.attach(RUN);
+        let tx = Tx {
+            client,
+            id: client,
+            amount: Decimal::from(amount),
+        };
+        account.apply(Transaction::Deposit(tx)).unwrap();
+    }
+
+    fn total(store: &mut FileStore, client: ClientId) -> Decimal {
+        store
+            .get(client, false)
+            .unwrap()
+            .unwrap()
+            .book()
+            .total_funds()
+    }
+
+    fn filled(clients: ClientId) -> (TempDir, Arc<Database>) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir.path().join("accounts.redb")).unwrap();
+        let mut store = store(&db, 100, 1, 0, RUN);
+        for client in 1..=clients {
+            deposit(&mut store, client, client as i64);
+        }
+        store.flush().unwrap();
+        (dir, db)
+    }
+
+    #[test]
+    fn accounts_outlive_the_file_being_closed() {
+        let (dir, db) = filled(2);
+        drop(db);
+        let path = dir.path().join("accounts.redb");
+
+        let db = open(&path).unwrap();
+        assert_eq!(total(&mut store(&db, 100, 1, 0, 2), 2), Decimal::from(2));
+        assert!(store(&db, 100, 1, 0, 2).get(3, false).unwrap().is_none());
+        drop(db);
+
+        assert_eq!(snapshot(&path, None).unwrap().len(), 2);
+        let only = snapshot(&path, Some(1)).unwrap();
+        assert_eq!(only[0].book().total_funds(), Decimal::ONE);
+        assert!(snapshot(&path, Some(3)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_full_cache_writes_back_its_older_half() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir.path().join("accounts.redb")).unwrap();
+        let mut store = store(&db, 2, 1, 0, RUN);
+        for client in 1..=3 {
+            deposit(&mut store, client, 10);
+        }
+        assert_eq!(store.cache.keys().copied().collect::<Vec<_>>(), [2, 3]);
+        assert!(store.load(1).unwrap().is_some());
+        assert_eq!(total(&mut store, 1), Decimal::from(10));
+    }
+
+    #[test]
+    fn for_each_walks_the_shards_accounts_a_chunk_at_a_time() {
+        let (_dir, db) = filled(5);
+        // note: written by one shard, read back by the second of two
+        let mut store = store(&db, 2, 2, 1, 2);
+        let mut visited = vec![];
+        store
+            .for_each(&mut |account| visited.push(account.client()))
+            .unwrap();
+        assert_eq!(visited, [1, 3, 5]);
+    }
+
+    #[test]
+    fn only_accounts_the_run_touched_are_written_back() {
+        let (_dir, db) = filled(2);
+        let mut store = store(&db, 100, 1, 0, 2);
+        store
+            .for_each(&mut |account| {
+                if account.client() == 2 {
+                    account.attach(2);
+                }
+                account.set_credit_limit(Decimal::from(5)).unwrap();
+            })
+            .unwrap();
+        assert_eq!(
+            store.load(1).unwrap().unwrap().credit_limit(),
+            Decimal::ZERO
+        );
+        assert_eq!(
+            store.load(2).unwrap().unwrap().credit_limit(),
+            Decimal::from(5)
+        );
+    }
+
+    #[test]
+    fn locked_clients_are_counted_from_their_index() {
+        let (_dir, db) = filled(3);
+        let mut writer = store(&db, 100, 1, 0, RUN);
+        let account = writer.get(3, false).unwrap().unwrap();
+        let tx = Tx {
+            client: 3,
+            id: 3,
+            amount: Decimal::ZERO,
+        };
+        account.apply(Transaction::Dispute(tx)).unwrap();
+        account.apply(Transaction::Chargeback(tx)).unwrap();
+        assert_eq!(writer.locked().unwrap(), 1);
+        writer.flush().unwrap();
+
+        assert_eq!(store(&db, 100, 2, 1, 2).locked().unwrap(), 1);
+        assert_eq!(store(&db, 100, 2, 0, 2).locked().unwrap(), 0);
+    }
+
+    #[test]
+    fn an_older_file_is_indexed_when_opened() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("accounts.redb");
+        let mut account = Account::new(4);
+        let tx = Tx {
+            client: 4,
+            id: 1,
+            amount: Decimal::ONE,
+        };
+        account.apply(Transaction::Deposit(tx)).unwrap();
+        account.apply(Transaction::Dispute(tx)).unwrap();
+        account.apply(Transaction::Chargeback(tx)).unwrap();
+        {
+            let db = Database::create(&path).unwrap();
+            let txn = db.begin_write().unwrap();
+            txn.open_table(ACCOUNTS)
+                .unwrap()
+                .insert(4, postcard::to_stdvec(&account).unwrap().as_slice())
+                .unwrap();
+            txn.commit().unwrap();
+        }
+        let db = open(&path).unwrap();
+        assert_eq!(store(&db, 100, 1, 0, RUN).locked().unwrap(), 1);
+    }
+}
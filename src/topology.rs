@@ -0,0 +1,90 @@
+use core_affinity::CoreId;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use tracing::warn;
+
+/// a core list such as `0-3,6,8-9`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoreList(pub Vec<usize>);
+
+impl fmt::Display for CoreList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cores: Vec<String> = self.0.iter().map(|core| core.to_string()).collect();
+        f.write_str(&cores.join(","))
+    }
+}
+
+impl FromStr for CoreList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_core_list(s).map(CoreList)
+    }
+}
+
+/// parses an id list such as `0-3,6,8-9` into its ranges, `what` names the
+/// ids in errors
+pub fn parse_ranges<T>(s: &str, what: &str) -> Result<Vec<RangeInclusive<T>>, String>
+where
+    T: FromStr + PartialOrd + Copy,
+    T::Err: fmt::Display,
+{
+    let mut ranges = vec![];
+    for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let parse = |n: &str| {
+            n.trim()
+                .parse::<T>()
+                .map_err(|e| format!("bad {} id {:?}: {}", what, n, e))
+        };
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end {
+                    return Err(format!("empty {} range {}", what, part));
+                }
+                ranges.push(start..=end);
+            }
+            None => {
+                let id = parse(part)?;
+                ranges.push(id..=id);
+            }
+        }
+    }
+    if ranges.is_empty() {
+        return Err(format!("{} list is empty", what));
+    }
+    Ok(ranges)
+}
+
+/// parses a core list such as `0-3,6,8-9`
+pub fn parse_core_list(s: &str) -> Result<Vec<usize>, String> {
+    Ok(parse_ranges(s, "core")?.into_iter().flatten().collect())
+}
+
+/// ids of the cores this process may run on
+pub fn available_cores() -> Vec<usize> {
+    core_affinity::get_core_ids()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|core| core.id)
+        .collect()
+}
+
+pub fn validate_cores(cores: &[usize]) -> anyhow::Result<()> {
+    let available = available_cores();
+    match cores.iter().find(|core| !available.contains(core)) {
+        Some(core) => Err(anyhow::anyhow!(
+            "core {} is not available to this process, available cores are {:?}",
+            core,
+            available
+        )),
+        None => Ok(()),
+    }
+}
+
+pub fn pin_current(core: usize) {
+    if !core_affinity::set_for_current(CoreId { id: core }) {
+        warn!(core, "failed to pin thread to core");
+    }
+}
@@ -1,22 +1,63 @@
 use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
 
-#[derive(Debug, Copy, Clone)]
+/// 64 bit so every partner can keep its own client ids
+pub type ClientId = u64;
+/// 64 bit so tx ids from several partners do not collide
+pub type TxId = u64;
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct Tx {
-    pub client: u16,
-    pub id: u32,
+    pub client: ClientId,
+    pub id: TxId,
     pub amount: Decimal,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub enum Transaction {
     Deposit(Tx),
     PendingWithdrawal(Tx),
     Dispute(Tx),
     Resolve(Tx),
     Chargeback(Tx),
+    /// admin transaction setting the client's credit limit to `amount`
+    CreditLimit(Tx),
+}
+
+/// the unit sent down a shard channel
+#[derive(Debug)]
+pub struct TxBatch {
+    pub ingested_at: Instant,
+    pub txs: Vec<Transaction>,
 }
 
 pub struct PendingWithdraw {
     pub arrival_time: u64,
     pub tx: Tx,
 }
+
+impl Transaction {
+    pub fn tx(&self) -> Tx {
+        match self {
+            Transaction::Deposit(tx)
+            | Transaction::PendingWithdrawal(tx)
+            | Transaction::Dispute(tx)
+            | Transaction::Resolve(tx)
+            | Transaction::Chargeback(tx)
+            | Transaction::CreditLimit(tx) => *tx,
+        }
+    }
+
+    /// the type as it is spelled in the input csv
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Transaction::Deposit(_) => "deposit",
+            Transaction::PendingWithdrawal(_) => "withdraw",
+            Transaction::Dispute(_) => "dispute",
+            Transaction::Resolve(_) => "resolve",
+            Transaction::Chargeback(_) => "chargeback",
+            Transaction::CreditLimit(_) => "limit",
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use kraken::engine::{Engine, EngineOptions};
+use kraken::fees::Fees;
+use kraken::io::ConcurrentAsyncFileDescriptorReader;
+use kraken::output::AccountOutput;
+use kraken::reference::{self, ReferenceModel};
+use kraken::retention::Horizon;
+use kraken::transaction::{Transaction, Tx};
+use proptest::prelude::*;
+use rust_decimal::Decimal;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::thread;
+
+#[derive(Debug, Copy, Clone)]
+enum Kind {
+    Deposit,
+    Withdraw,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Limit,
+}
+
+fn kind() -> impl Strategy<Value = Kind> {
+    prop_oneof![
+        4 => Just(Kind::Deposit),
+        3 => Just(Kind::Withdraw),
+        2 => Just(Kind::Dispute),
+        1 => Just(Kind::Resolve),
+        1 => Just(Kind::Chargeback),
+        1 => Just(Kind::Limit),
+    ]
+}
+
+/// what both sides are set up with besides the transactions
+#[derive(Debug, Copy, Clone)]
+enum Settings {
+    Plain,
+    Horizon(u64),
+    Fees,
+}
+
+fn settings() -> impl Strategy<Value = Settings> {
+    prop_oneof![
+        Just(Settings::Plain),
+        (1u64..=8).prop_map(Settings::Horizon),
+        Just(Settings::Fees),
+    ]
+}
+
+// note: every client pays the default tier, flat fees and basis points both
+fn fees() -> Arc<Fees> {
+    let mut schedule = tempfile::Builder::new().suffix(".csv").tempfile().unwrap();
+    writeln!(
+        schedule,
+        "tier,type,flat,bps\ndefault,deposit,0.1,10\ndefault,withdraw,0.25,5\ndefault,chargeback,1,0"
+    )
+    .unwrap();
+    schedule.flush().unwrap();
+    Arc::new(Fees::load(schedule.path(), None).unwrap())
+}
+
+// note: disputes, resolves and chargebacks point back at an earlier row so they
+//       mostly land on real deposits, few clients and tx ids make duplicates and
+//       locked accounts show up in small cases.  limit rows open credit lines
+//       so withdrawals may take available below zero
+fn transactions(max_len: usize) -> impl Strategy<Value = Vec<Transaction>> {
+    let row = (
+        kind(),
+        1u64..=8,
+        1u64..=64,
+        0i64..=50_000_000,
+        any::<prop::sample::Index>(),
+    );
+    prop::collection::vec(row, 0..max_len).prop_map(|rows| {
+        let mut transactions: Vec<Transaction> = vec![];
+        for (kind, client, id, amount, target) in rows {
+            let tx = Tx {
+                client,
+                id,
+                amount: Decimal::new(amount, 5),
+            };
+            let reference = match transactions.len() {
+                0 => tx,
+                len => transactions[target.index(len)].tx(),
+            };
+            let reference = Tx {
+                amount: Decimal::ZERO,
+                ..reference
+            };
+            transactions.push(match kind {
+                Kind::Deposit => Transaction::Deposit(tx),
+                Kind::Withdraw => Transaction::PendingWithdrawal(tx),
+                Kind::Dispute => Transaction::Dispute(reference),
+                Kind::Resolve => Transaction::Resolve(reference),
+                Kind::Chargeback => Transaction::Chargeback(reference),
+                Kind::Limit => Transaction::CreditLimit(Tx {
+                    amount: Decimal::new(amount, 6),
+                    ..tx
+                }),
+            });
+        }
+        transactions
+    })
+}
+
+fn to_csv_row(transaction: &Transaction) -> String {
+    match transaction {
+        Transaction::Deposit(tx) => format!("deposit,{},{},{}", tx.client, tx.id, tx.amount),
+        Transaction::PendingWithdrawal(tx) => {
+            format!("withdraw,{},{},{}", tx.client, tx.id, tx.amount)
+        }
+        Transaction::Dispute(tx) => format!("dispute,{},{},", tx.client, tx.id),
+        Transaction::Resolve(tx) => format!("resolve,{},{},", tx.client, tx.id),
+        Transaction::Chargeback(tx) => format!("chargeback,{},{},", tx.client, tx.id),
+        Transaction::CreditLimit(tx) => format!("limit,{},{},{}", tx.client, tx.id, tx.amount),
+    }
+}
+
+/// pushes the transactions through the csv reader and the sharded engine
+fn run_engine(
+    workers: usize,
+    settings: Settings,
+    transactions: &[Transaction],
+) -> Vec<AccountOutput> {
+    let mut file = tempfile::Builder::new().suffix(".csv").tempfile().unwrap();
+    writeln!(file, "type,client,tx,amount").unwrap();
+    for transaction in transactions {
+        writeln!(file, "{}", to_csv_row(transaction)).unwrap();
+    }
+    file.flush().unwrap();
+
+    let options = EngineOptions {
+        workers,
+        pin: false,
+        dispute_window_ms: 0,
+        ..EngineOptions::default()
+    };
+    let options = match settings {
+        Settings::Plain => options,
+        Settings::Horizon(n) => EngineOptions {
+            dispute_horizon: Some(Horizon::Transactions(n)),
+            ..options
+        },
+        Settings::Fees => EngineOptions {
+            fees: Some(fees()),
+            ..options
+        },
+    };
+    let (engine, senders) = Engine::new(options, Arc::new(AtomicBool::new(false))).unwrap();
+    let handle = thread::spawn(move || engine.run());
+
+    let path = file.path().to_string_lossy().to_string();
+    // note: dropping the reader closes the channels, which is what stops the shards
+    ConcurrentAsyncFileDescriptorReader::new(senders)
+        .consume(vec![path])
+        .unwrap();
+
+    let mut outputs = handle.join().unwrap().unwrap();
+    outputs.sort_by_key(|output| output.client);
+    outputs
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn sharded_engine_matches_reference_model(
+        transactions in transactions(200),
+        workers in 1usize..=4,
+    ) {
+        let expected = reference::replay(transactions.iter().copied());
+        let actual = run_engine(workers, Settings::Plain, &transactions);
+        prop_assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn configured_engine_matches_configured_reference_model(
+        transactions in transactions(200),
+        workers in 1usize..=4,
+        settings in settings(),
+    ) {
+        let model = match settings {
+            Settings::Plain => ReferenceModel::new(),
+            Settings::Horizon(n) => ReferenceModel::new().with_dispute_horizon(Horizon::Transactions(n)),
+            Settings::Fees => ReferenceModel::new().with_fees(fees()),
+        };
+        let expected = reference::replay_with(model, transactions.iter().copied());
+        let actual = run_engine(workers, settings, &transactions);
+        prop_assert_eq!(actual, expected);
+    }
+}